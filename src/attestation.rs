@@ -0,0 +1,251 @@
+use anyhow::{anyhow, bail, Result};
+use secp256k1::{ecdsa::Signature, Message, PublicKey, Secp256k1, SecretKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Quote format version, following DCAP quotes
+pub const QUOTE_VERSION: u16 = 3;
+/// Attestation key type of a quote signed by the Intel quoting enclave
+pub const ATT_KEY_TYPE_ECDSA_P256: u16 = 2;
+/// Attestation key type of a quote signed by the mock quote generator
+pub const ATT_KEY_TYPE_MOCK: u16 = 0xffff;
+
+/// Seed of the well-known mock attestation key. Quotes signed with it
+/// prove nothing about the hardware and are only meant for testing.
+const MOCK_ATT_KEY_SEED: &str = "nostrust mock attestation key";
+
+/// Domain separator for the report data of a quote
+const REPORT_DATA_CONTEXT: &str = "nostrust attestation";
+
+/// Header of a quote (subset of the DCAP quote header)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct QuoteHeader {
+    pub version: u16,
+    pub att_key_type: u16,
+    pub qe_vendor_id: String,
+}
+
+/// The enclave report embedded in a quote. Byte fields are hex encoded.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReportBody {
+    pub cpusvn: String,
+    pub mrenclave: String,
+    pub mrsigner: String,
+    pub isvprodid: u16,
+    pub isvsvn: u16,
+    pub report_data: String,
+}
+
+/// A signed statement that an enclave with the given measurement
+/// produced the given report data.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Quote {
+    pub header: QuoteHeader,
+    pub report_body: ReportBody,
+    pub signature: String,
+}
+
+/// Attestation data returned by the relay as part of its info document
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Attestation {
+    pub quote: Quote,
+    /// Public key the relay uses for transport, bound in the report data
+    pub transport_pubkey: String,
+}
+
+/// Computes the report data binding a client nonce and the relay's
+/// transport public key: SHA-256 over both, zero-padded to 64 bytes.
+pub fn report_data(nonce: &[u8], transport_pubkey: &[u8]) -> [u8; 64] {
+    let mut hasher = Sha256::new();
+    hasher.update(REPORT_DATA_CONTEXT);
+    hasher.update(nonce);
+    hasher.update(transport_pubkey);
+
+    let mut report_data = [0u8; 64];
+    report_data[..32].copy_from_slice(&hasher.finalize());
+    report_data
+}
+
+/// Digest of the signed part of a quote
+fn quote_digest(header: &QuoteHeader, report_body: &ReportBody) -> [u8; 32] {
+    let serialized = serde_json::to_vec(&(header, report_body)).unwrap();
+    Sha256::digest(serialized).into()
+}
+
+fn mock_att_key() -> SecretKey {
+    let seed: [u8; 32] = Sha256::digest(MOCK_ATT_KEY_SEED).into();
+    SecretKey::from_slice(&seed).unwrap()
+}
+
+/// Turns an enclave report into a quote
+pub trait QuoteGenerator: Send + Sync {
+    fn generate(&self, report_body: ReportBody) -> Quote;
+}
+
+/// Checks that a quote was signed by a trusted attestation key
+pub trait QuoteVerifier {
+    fn verify_signature(&self, quote: &Quote) -> Result<()>;
+}
+
+/// Signs quotes with the well-known mock attestation key, so attestation
+/// can be exercised without SGX hardware or Intel services.
+#[derive(Debug, Default)]
+pub struct MockQuoteGenerator;
+
+impl QuoteGenerator for MockQuoteGenerator {
+    fn generate(&self, report_body: ReportBody) -> Quote {
+        let header = QuoteHeader {
+            version: QUOTE_VERSION,
+            att_key_type: ATT_KEY_TYPE_MOCK,
+            qe_vendor_id: hex::encode([0u8; 16]),
+        };
+
+        let secp = Secp256k1::new();
+        let message = Message::from_digest(quote_digest(&header, &report_body));
+        let signature = secp.sign_ecdsa(&message, &mock_att_key());
+
+        Quote {
+            header,
+            report_body,
+            signature: hex::encode(signature.serialize_compact()),
+        }
+    }
+}
+
+/// Accepts quotes signed with the mock attestation key
+#[derive(Debug, Default)]
+pub struct MockQuoteVerifier;
+
+impl QuoteVerifier for MockQuoteVerifier {
+    fn verify_signature(&self, quote: &Quote) -> Result<()> {
+        if quote.header.att_key_type != ATT_KEY_TYPE_MOCK {
+            bail!("quote is not signed by the mock attestation key");
+        }
+
+        let secp = Secp256k1::new();
+        let message = Message::from_digest(quote_digest(&quote.header, &quote.report_body));
+        let signature = Signature::from_compact(&hex::decode(&quote.signature)?)?;
+        let public_key = PublicKey::from_secret_key(&secp, &mock_att_key());
+
+        secp.verify_ecdsa(&message, &signature, &public_key)
+            .map_err(|_| anyhow!("invalid quote signature"))
+    }
+}
+
+/// Decodes a hex field of a quote and checks its length
+fn decode_field<const N: usize>(name: &str, value: &str) -> Result<[u8; N]> {
+    hex::decode(value)?
+        .try_into()
+        .map_err(|_| anyhow!("quote field `{}` must be {} bytes", name, N))
+}
+
+impl Quote {
+    /// The MRENCLAVE measurement of the quoted enclave
+    pub fn mrenclave(&self) -> Result<[u8; 32]> {
+        decode_field("mrenclave", &self.report_body.mrenclave)
+    }
+
+    /// The MRSIGNER measurement of the quoted enclave
+    pub fn mrsigner(&self) -> Result<[u8; 32]> {
+        decode_field("mrsigner", &self.report_body.mrsigner)
+    }
+
+    /// Checks the version and the length of every byte field
+    pub fn verify_structure(&self) -> Result<()> {
+        if self.header.version != QUOTE_VERSION {
+            bail!("unsupported quote version {}", self.header.version);
+        }
+        decode_field::<16>("qe_vendor_id", &self.header.qe_vendor_id)?;
+        decode_field::<16>("cpusvn", &self.report_body.cpusvn)?;
+        decode_field::<64>("report_data", &self.report_body.report_data)?;
+        self.mrenclave()?;
+        self.mrsigner()?;
+        Ok(())
+    }
+}
+
+impl Attestation {
+    /// Verifies the quote and that it was produced in answer to `nonce`
+    /// for the advertised transport key. Returns the quoted MRENCLAVE.
+    pub fn verify(&self, verifier: &dyn QuoteVerifier, nonce: &[u8]) -> Result<[u8; 32]> {
+        self.quote.verify_structure()?;
+        verifier.verify_signature(&self.quote)?;
+
+        let transport_pubkey = hex::decode(&self.transport_pubkey)?;
+        let expected = report_data(nonce, &transport_pubkey);
+        let actual: [u8; 64] = decode_field("report_data", &self.quote.report_body.report_data)?;
+        if actual != expected {
+            bail!("quote does not match the nonce and transport key");
+        }
+
+        self.quote.mrenclave()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attestation(nonce: &[u8]) -> Attestation {
+        let transport_pubkey = hex::encode([2u8; 33]);
+        let report_data = report_data(nonce, &hex::decode(&transport_pubkey).unwrap());
+        let quote = MockQuoteGenerator.generate(ReportBody {
+            cpusvn: hex::encode([0u8; 16]),
+            mrenclave: hex::encode([0xabu8; 32]),
+            mrsigner: hex::encode([0xcdu8; 32]),
+            isvprodid: 0,
+            isvsvn: 0,
+            report_data: hex::encode(report_data),
+        });
+
+        Attestation {
+            quote,
+            transport_pubkey,
+        }
+    }
+
+    #[test]
+    fn test_mock_quote_verify_pass() {
+        let nonce = [7u8; 32];
+        let mrenclave = attestation(&nonce)
+            .verify(&MockQuoteVerifier, &nonce)
+            .unwrap();
+        assert_eq!(mrenclave, [0xab; 32]);
+    }
+
+    #[test]
+    fn test_quote_verify_fail_nonce() {
+        assert!(attestation(&[7u8; 32])
+            .verify(&MockQuoteVerifier, &[8u8; 32])
+            .is_err());
+    }
+
+    #[test]
+    fn test_quote_verify_fail_measurement() {
+        let nonce = [7u8; 32];
+        let mut attestation = attestation(&nonce);
+
+        // claiming a different enclave should break the signature
+        attestation.quote.report_body.mrenclave = hex::encode([0x11u8; 32]);
+
+        assert!(attestation.verify(&MockQuoteVerifier, &nonce).is_err());
+    }
+
+    #[test]
+    fn test_quote_verify_fail_transport_key() {
+        let nonce = [7u8; 32];
+        let mut attestation = attestation(&nonce);
+        attestation.transport_pubkey = hex::encode([3u8; 33]);
+
+        assert!(attestation.verify(&MockQuoteVerifier, &nonce).is_err());
+    }
+
+    #[test]
+    fn test_quote_verify_fail_structure() {
+        let nonce = [7u8; 32];
+        let mut attestation = attestation(&nonce);
+        attestation.quote.header.version = 2;
+
+        assert!(attestation.verify(&MockQuoteVerifier, &nonce).is_err());
+    }
+}
@@ -0,0 +1,249 @@
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use anyhow::{anyhow, bail, Result};
+use base64::{engine::general_purpose, Engine};
+use rand::Rng;
+use secp256k1::{ecdh::SharedSecret, PublicKey, Secp256k1, SecretKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Associated data of client-to-relay messages, followed by the counter
+const REQUEST_AAD: &[u8] = b"nostrust request";
+/// Associated data of relay-to-client messages, so a request can't be
+/// reflected back to the client as a response. Followed by the hash of
+/// the request, so a response only answers the request it was sent for.
+const RESPONSE_AAD: &[u8] = b"nostrust response";
+
+/// How far a request counter may be from the relay's clock. The relay
+/// only has to remember the last counter of sessions active this recently.
+pub const COUNTER_WINDOW: Duration = Duration::from_secs(5 * 60);
+
+/// A client message encrypted for the relay enclave
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EncryptedMessage {
+    /// The client's session public key (hex)
+    pub pubkey: String,
+    /// Rises with every request of the session, and starts from the time in
+    /// microseconds, so the relay can refuse requests it has seen before
+    pub counter: u64,
+    /// base64(nonce || ciphertext)
+    pub payload: String,
+}
+
+/// A channel between a client and the relay enclave, keyed by ECDH between
+/// the client's session key and the relay's attested transport key.
+pub struct Session {
+    key: [u8; 32],
+    pubkey: PublicKey,
+    /// Counter of the last request sent
+    counter: AtomicU64,
+}
+
+/// Microseconds since the Unix epoch, the scale request counters are on
+pub fn counter_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_micros() as u64)
+}
+
+impl Session {
+    /// Client side: `client_key` is the client's session key and
+    /// `relay_pubkey` the transport key from the relay's attestation.
+    pub fn client(client_key: &SecretKey, relay_pubkey: &PublicKey) -> Self {
+        Self {
+            key: SharedSecret::new(relay_pubkey, client_key).secret_bytes(),
+            pubkey: PublicKey::from_secret_key(&Secp256k1::new(), client_key),
+            counter: AtomicU64::new(0),
+        }
+    }
+
+    /// Relay side: `transport_key` is the relay's transport key and
+    /// `client_pubkey` the session key sent along with the message.
+    pub fn relay(transport_key: &SecretKey, client_pubkey: &PublicKey) -> Self {
+        Self {
+            key: SharedSecret::new(client_pubkey, transport_key).secret_bytes(),
+            pubkey: *client_pubkey,
+            counter: AtomicU64::new(0),
+        }
+    }
+
+    /// Encrypt a request for the relay
+    pub fn encrypt_request(&self, plaintext: &[u8]) -> Result<EncryptedMessage> {
+        let counter = self.next_counter();
+        Ok(EncryptedMessage {
+            pubkey: hex::encode(self.pubkey.serialize()),
+            counter,
+            payload: self.encrypt(plaintext, &request_aad(counter))?,
+        })
+    }
+
+    /// Decrypt a request. The counter is authenticated, but checking that
+    /// it rises is up to the caller.
+    pub fn decrypt_request(&self, message: &EncryptedMessage) -> Result<Vec<u8>> {
+        self.decrypt(&message.payload, &request_aad(message.counter))
+    }
+
+    /// Encrypt the response to `request` for the client
+    pub fn encrypt_response(&self, request: &EncryptedMessage, plaintext: &[u8]) -> Result<String> {
+        self.encrypt(plaintext, &response_aad(request))
+    }
+
+    /// Decrypt the response to `request`
+    pub fn decrypt_response(&self, request: &EncryptedMessage, payload: &str) -> Result<Vec<u8>> {
+        self.decrypt(payload, &response_aad(request))
+    }
+
+    /// A counter above the last one, and no lower than the clock
+    fn next_counter(&self) -> u64 {
+        let now = counter_now();
+        let next = |last: u64| now.max(last + 1);
+        let last = self
+            .counter
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| Some(next(last)))
+            .unwrap();
+        next(last)
+    }
+
+    fn encrypt(&self, plaintext: &[u8], aad: &[u8]) -> Result<String> {
+        let cipher = Aes256Gcm::new_from_slice(&self.key)?;
+        let nonce: [u8; 12] = rand::thread_rng().gen();
+        let nonce = Nonce::from_slice(&nonce);
+
+        let ciphertext = cipher
+            .encrypt(
+                nonce,
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .map_err(|_| anyhow!("encryption failed"))?;
+
+        let mut result = Vec::new();
+        result.extend_from_slice(nonce);
+        result.extend_from_slice(&ciphertext);
+        Ok(general_purpose::STANDARD.encode(&result))
+    }
+
+    fn decrypt(&self, payload: &str, aad: &[u8]) -> Result<Vec<u8>> {
+        let cipher = Aes256Gcm::new_from_slice(&self.key)?;
+        let payload = general_purpose::STANDARD.decode(payload.trim())?;
+        if payload.len() < 12 {
+            bail!("ciphertext too short");
+        }
+
+        let nonce = Nonce::from_slice(&payload[..12]);
+        cipher
+            .decrypt(
+                nonce,
+                Payload {
+                    msg: &payload[12..],
+                    aad,
+                },
+            )
+            .map_err(|_| anyhow!("decryption failed"))
+    }
+}
+
+fn request_aad(counter: u64) -> Vec<u8> {
+    [REQUEST_AAD, &counter.to_be_bytes()].concat()
+}
+
+fn response_aad(request: &EncryptedMessage) -> Vec<u8> {
+    let request_hash = Sha256::new()
+        .chain_update(request.counter.to_be_bytes())
+        .chain_update(&request.payload)
+        .finalize();
+    [RESPONSE_AAD, request_hash.as_slice()].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keypair(seed: u8) -> (SecretKey, PublicKey) {
+        let secret = SecretKey::from_slice(&[seed; 32]).unwrap();
+        let public = PublicKey::from_secret_key(&Secp256k1::new(), &secret);
+        (secret, public)
+    }
+
+    #[test]
+    fn test_session_roundtrip() {
+        let (client_secret, _) = keypair(1);
+        let (relay_secret, relay_public) = keypair(2);
+
+        let client = Session::client(&client_secret, &relay_public);
+        let request = client.encrypt_request(b"hello relay").unwrap();
+
+        let client_pubkey = PublicKey::from_slice(&hex::decode(&request.pubkey).unwrap()).unwrap();
+        let relay = Session::relay(&relay_secret, &client_pubkey);
+        assert_eq!(relay.decrypt_request(&request).unwrap(), b"hello relay");
+
+        let response = relay.encrypt_response(&request, b"hello client").unwrap();
+        assert_eq!(
+            client.decrypt_response(&request, &response).unwrap(),
+            b"hello client"
+        );
+    }
+
+    #[test]
+    fn test_session_wrong_relay_key() {
+        let (client_secret, _) = keypair(1);
+        let (_, relay_public) = keypair(2);
+        let (other_secret, _) = keypair(3);
+
+        let client = Session::client(&client_secret, &relay_public);
+        let request = client.encrypt_request(b"hello relay").unwrap();
+
+        // a host without the enclave's transport key can't read the request
+        let client_pubkey = PublicKey::from_slice(&hex::decode(&request.pubkey).unwrap()).unwrap();
+        let impostor = Session::relay(&other_secret, &client_pubkey);
+        assert!(impostor.decrypt_request(&request).is_err());
+    }
+
+    #[test]
+    fn test_session_request_not_reflected() {
+        let (client_secret, _) = keypair(1);
+        let (_, relay_public) = keypair(2);
+
+        let client = Session::client(&client_secret, &relay_public);
+        let request = client.encrypt_request(b"hello relay").unwrap();
+
+        assert!(client.decrypt_response(&request, &request.payload).is_err());
+    }
+
+    #[test]
+    fn test_counters_rise_and_are_authenticated() {
+        let (client_secret, client_public) = keypair(1);
+        let (relay_secret, relay_public) = keypair(2);
+        let client = Session::client(&client_secret, &relay_public);
+        let relay = Session::relay(&relay_secret, &client_public);
+
+        let first = client.encrypt_request(b"first").unwrap();
+        let second = client.encrypt_request(b"second").unwrap();
+        assert!(second.counter > first.counter);
+        assert!(first.counter.abs_diff(counter_now()) < COUNTER_WINDOW.as_micros() as u64);
+
+        // the host can't give a recorded request a fresh counter
+        let mut renumbered = first.clone();
+        renumbered.counter = second.counter + 1;
+        assert!(relay.decrypt_request(&renumbered).is_err());
+    }
+
+    #[test]
+    fn test_response_only_answers_its_request() {
+        let (client_secret, client_public) = keypair(1);
+        let (relay_secret, relay_public) = keypair(2);
+        let client = Session::client(&client_secret, &relay_public);
+        let relay = Session::relay(&relay_secret, &client_public);
+
+        let first = client.encrypt_request(b"first").unwrap();
+        let second = client.encrypt_request(b"second").unwrap();
+        let response = relay.encrypt_response(&first, b"for the first").unwrap();
+
+        assert!(client.decrypt_response(&first, &response).is_ok());
+        assert!(client.decrypt_response(&second, &response).is_err());
+    }
+}
@@ -0,0 +1,228 @@
+use anyhow::{bail, Result};
+use core::attestation::MockQuoteVerifier;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+/// Where the client keeps its configuration
+pub const CONFIG_PATH: &str = "client.toml";
+
+/// Measurements the relay enclave is expected to have (hex encoded)
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct AttestationConfig {
+    pub mrenclave: Option<String>,
+    pub mrsigner: Option<String>,
+    /// Accept quotes signed with the public mock attestation key. Anyone can
+    /// forge those, so this is only for testing against a mock relay.
+    #[serde(default)]
+    pub allow_mock: bool,
+}
+
+/// Highest difficulty mined by default. Each step doubles the work, and
+/// 28 bits take a few minutes on a laptop.
+const DEFAULT_MAX_POW_DIFFICULTY: usize = 28;
+
+/// Proof of work (NIP-13) mined into posts
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct PowConfig {
+    /// Difficulty to mine posts to, raised to the relay's minimum if that is higher
+    pub difficulty: usize,
+    /// Threads mining a post, 0 for one per CPU
+    pub threads: usize,
+    /// Highest difficulty to mine to. The relay's minimum comes from its
+    /// host and isn't attested, so a host could otherwise ask for mining
+    /// that never ends.
+    pub max_difficulty: usize,
+}
+
+impl Default for PowConfig {
+    fn default() -> Self {
+        Self {
+            difficulty: 0,
+            threads: 0,
+            max_difficulty: DEFAULT_MAX_POW_DIFFICULTY,
+        }
+    }
+}
+
+impl PowConfig {
+    /// Difficulty to mine a post to for a relay that requires `required`
+    pub fn target(&self, required: usize) -> Result<usize> {
+        let difficulty = self.difficulty.max(required);
+        if difficulty > self.max_difficulty {
+            bail!(
+                "proof of work of difficulty {} is more than the max_difficulty of {}",
+                difficulty,
+                self.max_difficulty
+            );
+        }
+        Ok(difficulty)
+    }
+
+    /// Threads to mine with
+    pub fn threads(&self) -> usize {
+        match self.threads {
+            0 => std::thread::available_parallelism().map_or(1, usize::from),
+            threads => threads,
+        }
+    }
+}
+
+/// How posts are published
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default)]
+pub struct PostsConfig {
+    /// Mark posts with `["-"]` so relays only take them from us (NIP-70)
+    pub protected: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct ClientConfig {
+    #[serde(default)]
+    pub attestation: AttestationConfig,
+    #[serde(default)]
+    pub pow: PowConfig,
+    #[serde(default)]
+    pub posts: PostsConfig,
+}
+
+impl ClientConfig {
+    /// Load the config from `path`, or an empty config if the file doesn't exist
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(contents) => Ok(toml::from_str(&contents)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        fs::write(path, toml::to_string(self)?)?;
+        Ok(())
+    }
+
+    /// Whether any expected measurement has been configured
+    pub fn is_pinned(&self) -> bool {
+        self.attestation.mrenclave.is_some() || self.attestation.mrsigner.is_some()
+    }
+
+    /// Verifier for the relay's quotes. Only mock quotes can be checked so
+    /// far, and those are refused unless `allow_mock` is set.
+    pub fn quote_verifier(&self) -> Result<MockQuoteVerifier> {
+        if !self.attestation.allow_mock {
+            bail!(
+                "only mock attestation is supported, which anyone can forge; set allow_mock in {} to accept it for testing",
+                CONFIG_PATH
+            );
+        }
+        Ok(MockQuoteVerifier)
+    }
+
+    /// Pin the measurements of the relay we are talking to (trust on first use)
+    pub fn pin(&mut self, mrenclave: &[u8; 32], mrsigner: &[u8; 32]) {
+        self.attestation.mrenclave = Some(hex::encode(mrenclave));
+        self.attestation.mrsigner = Some(hex::encode(mrsigner));
+    }
+
+    /// Compare the relay's measurements against the pinned ones
+    pub fn check_measurement(&self, mrenclave: &[u8; 32], mrsigner: &[u8; 32]) -> Result<()> {
+        if let Some(expected) = &self.attestation.mrenclave {
+            if !expected.eq_ignore_ascii_case(&hex::encode(mrenclave)) {
+                bail!(
+                    "relay MRENCLAVE changed: expected {}, got {}",
+                    expected,
+                    hex::encode(mrenclave)
+                );
+            }
+        }
+        if let Some(expected) = &self.attestation.mrsigner {
+            if !expected.eq_ignore_ascii_case(&hex::encode(mrsigner)) {
+                bail!(
+                    "relay MRSIGNER changed: expected {}, got {}",
+                    expected,
+                    hex::encode(mrsigner)
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_measurement_pinned() {
+        let mut config = ClientConfig::default();
+        config.pin(&[1; 32], &[2; 32]);
+
+        assert!(config.check_measurement(&[1; 32], &[2; 32]).is_ok());
+        assert!(config.check_measurement(&[3; 32], &[2; 32]).is_err());
+        assert!(config.check_measurement(&[1; 32], &[3; 32]).is_err());
+    }
+
+    #[test]
+    fn test_check_measurement_mrsigner_only() {
+        let config = ClientConfig {
+            attestation: AttestationConfig {
+                mrenclave: None,
+                mrsigner: Some(hex::encode([2u8; 32])),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        // any enclave build from the same signer is accepted
+        assert!(config.is_pinned());
+        assert!(config.check_measurement(&[9; 32], &[2; 32]).is_ok());
+        assert!(config.check_measurement(&[9; 32], &[3; 32]).is_err());
+    }
+
+    #[test]
+    fn test_mock_quotes_need_opt_in() {
+        let mut config = ClientConfig::default();
+        assert!(config.quote_verifier().is_err());
+
+        config = toml::from_str("[attestation]\nallow_mock = true").unwrap();
+        assert!(config.quote_verifier().is_ok());
+    }
+
+    #[test]
+    fn test_pow_target_is_capped() {
+        let pow = PowConfig {
+            difficulty: 8,
+            max_difficulty: 20,
+            ..Default::default()
+        };
+        assert_eq!(pow.target(0).unwrap(), 8);
+        assert_eq!(pow.target(20).unwrap(), 20);
+        // a host advertising a huge minimum can't make the client mine forever
+        assert!(pow.target(60).is_err());
+    }
+
+    #[test]
+    fn test_config_toml_roundtrip() {
+        let mut config = ClientConfig::default();
+        config.pin(&[1; 32], &[2; 32]);
+
+        let serialized = toml::to_string(&config).unwrap();
+        let deserialized: ClientConfig = toml::from_str(&serialized).unwrap();
+
+        assert_eq!(deserialized, config);
+        assert_eq!(
+            toml::from_str::<ClientConfig>("").unwrap(),
+            ClientConfig::default()
+        );
+
+        let config: ClientConfig = toml::from_str("[pow]\ndifficulty = 16").unwrap();
+        assert_eq!(config.pow.difficulty, 16);
+        assert!(config.pow.threads() >= 1);
+        assert_eq!(config.pow.max_difficulty, DEFAULT_MAX_POW_DIFFICULTY);
+        assert!(!config.posts.protected);
+
+        let config: ClientConfig = toml::from_str("[posts]\nprotected = true").unwrap();
+        assert!(config.posts.protected);
+    }
+}
@@ -1,21 +1,36 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use keys::get_user_by_pubkey;
 
+use crate::config::{ClientConfig, CONFIG_PATH};
 use crate::keys::generate_users;
+use crate::receipts::{check_receipt, store_receipt, RECEIPTS_PATH};
 use crate::terminal::{Command::*, SimplerTheme, TerminalInput};
 use chrono::{Local, TimeZone};
+use core::channel::Session;
 use core::event::Event;
 use core::filter::Filter;
+use core::http_auth::{authorization_header, http_auth_event};
 use core::info::Info;
-use core::message::ClientMessage;
+use core::message::{ClientMessage, DataExport, RelayMessage, AUTH_KIND};
 use dialoguer::{console::Style, Input};
+use secp256k1::{PublicKey, SecretKey};
 use serde::Serialize;
-use sha2::Digest;
 
 // mod message;
+mod config;
 mod keys;
+mod receipts;
 mod terminal;
 
+/// Where the client saves the copy of the user's data the relay sends
+const EXPORT_PATH: &str = "export.json";
+
+/// Saves the user's data as sent by the relay, replacing any earlier export
+fn save_export(export: &DataExport) -> Result<()> {
+    std::fs::write(EXPORT_PATH, serde_json::to_string_pretty(export)?)?;
+    Ok(())
+}
+
 /// Sends an HTTP message to the relay and returns the response. Used for all client-relay communication.
 pub fn send_http_message(ip: &str, port: u16, message: impl Serialize) -> Option<Vec<u8>> {
     let output = reqwest::blocking::Client::new()
@@ -34,6 +49,143 @@ pub fn send_http_message(ip: &str, port: u16, message: impl Serialize) -> Option
     }
 }
 
+/// Sends an admin request to the relay, signed with a NIP-98 auth event.
+/// Returns the status code and body of the response.
+pub fn send_admin_request(
+    ip: &str,
+    port: u16,
+    action: &str,
+    privkey: String,
+    pubkey: String,
+) -> Option<(u16, String)> {
+    let url = format!("http://{}:{}/admin/{}", ip, port, action);
+    let event = http_auth_event(privkey, pubkey, &url, "POST", b"");
+
+    let output = reqwest::blocking::Client::new()
+        .post(&url)
+        .header("Authorization", authorization_header(&event))
+        .send()
+        .ok()?;
+
+    let status = output.status().as_u16();
+    Some((status, output.text().unwrap_or_default()))
+}
+
+/// Sends a message through the encrypted channel to the relay enclave and
+/// returns the decrypted response. Nothing readable leaves the client.
+pub fn send_encrypted_message(
+    ip: &str,
+    port: u16,
+    session: &Session,
+    message: ClientMessage,
+) -> Option<Vec<u8>> {
+    let plaintext = serde_json::to_vec(&message).ok()?;
+    let encrypted = session.encrypt_request(&plaintext).ok()?;
+
+    let output = send_http_message(ip, port, ClientMessage::Encrypted(encrypted.clone()))?;
+    session
+        .decrypt_response(&encrypted, &String::from_utf8_lossy(&output))
+        .ok()
+}
+
+/// Prints why the relay refused a message, if it did
+fn report_rejection(output: Option<Vec<u8>>, error: &Style) {
+    let Some(output) = output else {
+        eprintln!("{}", error.apply_to("> relay did not answer."));
+        return;
+    };
+    match RelayMessage::from_body(&output) {
+        Ok(RelayMessage::Ok(_, false, reason))
+        | Ok(RelayMessage::Closed(_, reason))
+        | Ok(RelayMessage::Notice(reason)) => {
+            eprintln!("{}", error.apply_to(format!("> relay refused: {}", reason)));
+        }
+        _ => {}
+    }
+}
+
+/// Opens an encrypted channel to the transport key attested in `info`
+fn open_session(info: &Info) -> Result<Session> {
+    let attestation = info
+        .attestation
+        .as_ref()
+        .ok_or_else(|| anyhow!("relay did not send an attestation"))?;
+    let relay_pubkey = PublicKey::from_slice(&hex::decode(&attestation.transport_pubkey)?)?;
+
+    // fresh session key for every channel
+    let session_key = loop {
+        if let Ok(key) = SecretKey::from_slice(&rand::random::<[u8; 32]>()) {
+            break key;
+        }
+    };
+
+    Ok(Session::client(&session_key, &relay_pubkey))
+}
+
+/// Authenticates `session` as `pubkey` by signing the relay's challenge
+/// (NIP-42), so the relay lets it read and change the user's feed
+fn authenticate(
+    ip: &str,
+    port: u16,
+    session: Session,
+    privkey: &str,
+    pubkey: &str,
+) -> Result<Session> {
+    let output = send_encrypted_message(ip, port, &session, ClientMessage::Challenge)
+        .ok_or_else(|| anyhow!("relay is unreachable"))?;
+    let RelayMessage::Auth(challenge) = RelayMessage::from_body(&output)? else {
+        return Err(anyhow!("relay did not send a challenge"));
+    };
+
+    let event = Event::new(
+        privkey.to_string(),
+        pubkey.to_string(),
+        AUTH_KIND,
+        vec![
+            vec!["relay".to_string(), format!("http://{}:{}/", ip, port)],
+            vec!["challenge".to_string(), challenge],
+        ],
+        String::new(),
+    );
+    let output = send_encrypted_message(ip, port, &session, ClientMessage::Auth(event))
+        .ok_or_else(|| anyhow!("relay is unreachable"))?;
+    match RelayMessage::from_body(&output)? {
+        RelayMessage::Ok(_, true, _) => Ok(session),
+        RelayMessage::Ok(_, false, reason) => Err(anyhow!("authentication failed: {}", reason)),
+        _ => Err(anyhow!("relay did not answer the authentication")),
+    }
+}
+
+/// Fetches the relay info and verifies its attestation quote against the
+/// measurements pinned in `config`. Pins the relay on first use.
+fn attest_relay(ip: &str, port: u16, config: &mut ClientConfig) -> Result<Info> {
+    // fresh nonce so an old quote can't be replayed to us
+    let nonce: [u8; 32] = rand::random();
+    let output = send_http_message(ip, port, ClientMessage::Info(hex::encode(nonce)))
+        .ok_or_else(|| anyhow!("relay is unreachable"))?;
+    let info: Info = serde_json::from_slice(&output)?;
+
+    let attestation = info
+        .attestation
+        .as_ref()
+        .ok_or_else(|| anyhow!("relay did not send an attestation"))?;
+    let mrenclave = attestation.verify(&config.quote_verifier()?, &nonce)?;
+    let mrsigner = attestation.quote.mrsigner()?;
+
+    if !config.is_pinned() {
+        config.pin(&mrenclave, &mrsigner);
+        config.save(CONFIG_PATH)?;
+        println!(
+            "Pinned relay measurement {} in {}",
+            hex::encode(mrenclave),
+            CONFIG_PATH
+        );
+    }
+    config.check_measurement(&mrenclave, &mrsigner)?;
+
+    Ok(info)
+}
+
 fn main() -> Result<()> {
     let dim = Style::new().for_stderr().dim();
     let motd = r#"
@@ -49,9 +201,20 @@ fn main() -> Result<()> {
     "#;
 
     println!("{}", dim.apply_to(motd));
+    let error = Style::new().for_stderr().red();
 
     let users = generate_users();
     let (ip, port) = ("localhost", 8080);
+    let mut config = ClientConfig::load(CONFIG_PATH)?;
+    if config.attestation.allow_mock {
+        eprintln!(
+            "{}",
+            error.apply_to(format!(
+                "> WARNING: allow_mock is set in {}, so any relay can fake its attestation",
+                CONFIG_PATH
+            ))
+        );
+    }
 
     let chosen_user: String = Input::with_theme(&SimplerTheme::default())
         .with_prompt("> log in: ")
@@ -69,6 +232,21 @@ fn main() -> Result<()> {
     let privkey = hex::encode(credentials.private_key.secret_bytes());
     let pubkey = hex::encode(credentials.public_key.serialize());
 
+    let mut session = match attest_relay(ip, port, &mut config)
+        .and_then(|info| open_session(&info))
+        .and_then(|session| authenticate(ip, port, session, &privkey, &pubkey))
+    {
+        Ok(session) => session,
+        Err(e) => {
+            eprintln!(
+                "{}",
+                error.apply_to(format!("> relay attestation failed: {}", e))
+            );
+            eprintln!("{}", error.apply_to("> refusing to talk to this relay."));
+            return Err(e);
+        }
+    };
+
     loop {
         let input: TerminalInput = Input::with_theme(&SimplerTheme::default())
             .with_prompt("> ")
@@ -80,18 +258,69 @@ fn main() -> Result<()> {
             Post => {
                 let content = input.argument.unwrap();
 
-                let event = Event::new(
-                    privkey.clone(),
-                    pubkey.clone(),
-                    1,
-                    vec![],
-                    content,
-                );
+                // never hand our data to a relay whose enclave changed since login
+                let info = match attest_relay(ip, port, &mut config) {
+                    Ok(info) => info,
+                    Err(e) => {
+                        eprintln!(
+                            "{}",
+                            error.apply_to(format!("> relay attestation failed: {}", e))
+                        );
+                        eprintln!("{}", error.apply_to("> post was not sent."));
+                        continue;
+                    }
+                };
+                match open_session(&info)
+                    .and_then(|session| authenticate(ip, port, session, &privkey, &pubkey))
+                {
+                    Ok(new_session) => session = new_session,
+                    Err(e) => {
+                        eprintln!("{}", error.apply_to(format!("> {}", e)));
+                        continue;
+                    }
+                }
 
-                let message = ClientMessage::Event(event);
+                let required = info
+                    .limitation
+                    .and_then(|limitation| limitation.min_pow_difficulty)
+                    .unwrap_or(0);
+                let difficulty = match config.pow.target(required) {
+                    Ok(difficulty) => difficulty,
+                    Err(e) => {
+                        eprintln!("{}", error.apply_to(format!("> refusing to post: {}", e)));
+                        continue;
+                    }
+                };
+                let tags = if config.posts.protected {
+                    vec![vec!["-".to_string()]]
+                } else {
+                    vec![]
+                };
+                let event = if difficulty > 0 {
+                    let started = std::time::Instant::now();
+                    let event = Event::new_with_pow(
+                        privkey.clone(),
+                        pubkey.clone(),
+                        1,
+                        tags,
+                        content,
+                        difficulty,
+                        config.pow.threads(),
+                    );
+                    println!(
+                        "Mined proof of work of difficulty {} in {:.1?}",
+                        event.difficulty(),
+                        started.elapsed()
+                    );
+                    event
+                } else {
+                    Event::new(privkey.clone(), pubkey.clone(), 1, tags, content)
+                };
 
-                send_http_message(ip, port, message);
+                let message = ClientMessage::Event(event);
 
+                let output = send_encrypted_message(ip, port, &session, message);
+                report_rejection(output, &error);
             }
             Follow => {
                 let author = input.argument.unwrap();
@@ -107,7 +336,8 @@ fn main() -> Result<()> {
                 let filter = Filter::one_author(author_pubkey_str.clone());
                 let message = ClientMessage::Req(user_pubkey_str, vec![filter]);
 
-                send_http_message(ip, port, message);
+                let output = send_encrypted_message(ip, port, &session, message);
+                report_rejection(output, &error);
             }
             Unfollow => {
                 let author = input.argument.unwrap();
@@ -124,9 +354,36 @@ fn main() -> Result<()> {
 
                 let message = ClientMessage::Close(user_pubkey_str, vec![filter]);
 
-                send_http_message(ip, port, message);
+                let output = send_encrypted_message(ip, port, &session, message);
+                report_rejection(output, &error);
             }
             Delete => {
+                // the receipt is only proof if it is signed by an attested enclave
+                let info = match attest_relay(ip, port, &mut config) {
+                    Ok(info) => info,
+                    Err(e) => {
+                        eprintln!(
+                            "{}",
+                            error.apply_to(format!("> relay attestation failed: {}", e))
+                        );
+                        eprintln!("{}", error.apply_to("> deletion was not sent."));
+                        continue;
+                    }
+                };
+                match open_session(&info)
+                    .and_then(|session| authenticate(ip, port, session, &privkey, &pubkey))
+                {
+                    Ok(new_session) => session = new_session,
+                    Err(e) => {
+                        eprintln!("{}", error.apply_to(format!("> {}", e)));
+                        continue;
+                    }
+                }
+                let transport_pubkey = info
+                    .attestation
+                    .map(|attestation| attestation.transport_pubkey)
+                    .unwrap_or_default();
+
                 let event = Event::new(
                     privkey.clone(),
                     pubkey.clone(),
@@ -134,13 +391,75 @@ fn main() -> Result<()> {
                     vec![],
                     "deletion request".to_string(),
                 );
-                let message = ClientMessage::Event(event);
-                send_http_message(ip, port, message);
+                let message = ClientMessage::Event(event.clone());
+                let output = send_encrypted_message(ip, port, &session, message);
+
+                let receipt = match output.as_deref().map(RelayMessage::from_body) {
+                    Some(Ok(RelayMessage::Receipt(receipt))) => receipt,
+                    // erased, but the relay couldn't save it yet
+                    Some(Ok(RelayMessage::Ok(_, true, message))) => {
+                        eprintln!(
+                            "{}",
+                            error.apply_to(format!("> no receipt yet: {}", message))
+                        );
+                        continue;
+                    }
+                    _ => {
+                        report_rejection(output, &error);
+                        continue;
+                    }
+                };
+                if let Err(e) = check_receipt(&receipt, &event, &transport_pubkey) {
+                    eprintln!(
+                        "{}",
+                        error.apply_to(format!("> erasure receipt is not valid: {}", e))
+                    );
+                    continue;
+                }
+                match store_receipt(RECEIPTS_PATH, &receipt) {
+                    Ok(()) => println!(
+                        "Erased {} events, from sealed state version {} on. Receipt saved in {}",
+                        receipt.event_ids.len(),
+                        receipt.sealed_version,
+                        RECEIPTS_PATH
+                    ),
+                    Err(e) => eprintln!(
+                        "{}",
+                        error.apply_to(format!("> could not save the erasure receipt: {}", e))
+                    ),
+                }
+            }
+            Export => {
+                let message = ClientMessage::Export(pubkey.clone());
+                let output = send_encrypted_message(ip, port, &session, message);
+                let export = match output.as_deref().map(RelayMessage::from_body) {
+                    Some(Ok(RelayMessage::Export(export))) => export,
+                    _ => {
+                        report_rejection(output, &error);
+                        continue;
+                    }
+                };
+                match save_export(&export) {
+                    Ok(()) => println!(
+                        "Exported {} events and {} follows to {}",
+                        export.events.len(),
+                        export.follows.len(),
+                        EXPORT_PATH
+                    ),
+                    Err(e) => eprintln!(
+                        "{}",
+                        error.apply_to(format!("> could not save the export: {}", e))
+                    ),
+                }
             }
             Get => {
                 let user_pubkey_str = hex::encode(credentials.public_key.serialize());
-                let output_data =
-                    send_http_message(ip, port, ClientMessage::Get(user_pubkey_str.clone()));
+                let output_data = send_encrypted_message(
+                    ip,
+                    port,
+                    &session,
+                    ClientMessage::Get(user_pubkey_str.clone()),
+                );
 
                 if let Some(data) = output_data {
                     let events: Result<Vec<Event>, _> = serde_json::from_slice(&data);
@@ -160,27 +479,46 @@ fn main() -> Result<()> {
                     }
                 }
             }
-            #[allow(deprecated)]
-            Info => {
-                let output_info =
-                    send_http_message(ip, port, ClientMessage::Info);
-                    let info: Result<Info, _> = serde_json::from_slice(&output_info.unwrap());
-                    if let Ok(info) = info {
-                        let measurement = base64::encode(
-                            sha2::Sha256::digest(info.attestation)
-                        );
-                        println!("Relay Info:");
-                        println!("Name: {}", info.name);
-                        println!("Version: {}", info.version);
-                        println!("Description: {}", info.description);
-                        println!("Attestation: {}", measurement);
-                        println!("Icon: {}", info.icon.unwrap());
-                        println!("Software: {}", info.software);
+            Info => match attest_relay(ip, port, &mut config) {
+                Ok(info) => {
+                    // the relay may have restarted with a new transport key
+                    if let Ok(new_session) = open_session(&info)
+                        .and_then(|session| authenticate(ip, port, session, &privkey, &pubkey))
+                    {
+                        session = new_session;
                     }
+                    // attest_relay only succeeds if the relay sent an attestation
+                    let measurement = info
+                        .attestation
+                        .map(|attestation| attestation.quote.report_body.mrenclave)
+                        .unwrap_or_default();
+                    println!("Relay Info:");
+                    println!("Name: {}", info.name);
+                    println!("Version: {}", info.version);
+                    println!("Description: {}", info.description);
+                    println!("Attestation: {} (verified)", measurement);
+                    println!("Icon: {}", info.icon.unwrap_or_default());
+                    println!("Software: {}", info.software);
+                }
+                Err(e) => eprintln!(
+                    "{}",
+                    error.apply_to(format!("> relay attestation failed: {}", e))
+                ),
+            },
+            Admin => {
+                let action = input.argument.unwrap();
+                match send_admin_request(ip, port, &action, privkey.clone(), pubkey.clone()) {
+                    Some((200..=299, body)) => println!("{}", body),
+                    Some((status, body)) => eprintln!(
+                        "{}",
+                        error.apply_to(format!("> admin request failed ({}): {}", status, body))
+                    ),
+                    None => eprintln!("{}", error.apply_to("> relay is unreachable")),
+                }
             }
             Help => println!(
                 "The following commands are available: {}",
-                [Post, Follow, Unfollow, Get, Delete, Info, Help, Quit]
+                [Post, Follow, Unfollow, Get, Delete, Export, Info, Admin, Help, Quit]
                     .iter()
                     .map(|item| item.to_string())
                     .collect::<Vec<String>>()
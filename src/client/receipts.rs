@@ -0,0 +1,127 @@
+use anyhow::{bail, Result};
+use core::event::Event;
+use core::receipt::ErasureReceipt;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+
+/// Where the client keeps the erasure receipts it got, one JSON object per line
+pub const RECEIPTS_PATH: &str = "receipts.jsonl";
+
+/// Check that `receipt` answers `request` and was signed by the enclave
+/// whose attested transport key is `transport_pubkey` (hex encoded)
+pub fn check_receipt(
+    receipt: &ErasureReceipt,
+    request: &Event,
+    transport_pubkey: &str,
+) -> Result<()> {
+    if !receipt.verify() {
+        bail!("receipt signature is invalid");
+    }
+    if !receipt.relay_pubkey.eq_ignore_ascii_case(transport_pubkey) {
+        bail!(
+            "receipt was signed by {}, not the attested enclave key {}",
+            receipt.relay_pubkey,
+            transport_pubkey
+        );
+    }
+    if receipt.request_id != request.id || receipt.request_kind != request.kind {
+        bail!(
+            "receipt is for request {}, not {}",
+            receipt.request_id,
+            request.id
+        );
+    }
+    if receipt.pubkey != request.pubkey {
+        bail!(
+            "receipt is for user {}, not {}",
+            receipt.pubkey,
+            request.pubkey
+        );
+    }
+    Ok(())
+}
+
+/// Append `receipt` to the receipts file at `path`
+pub fn store_receipt(path: impl AsRef<Path>, receipt: &ErasureReceipt) -> Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", serde_json::to_string(receipt)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secp256k1::{PublicKey, Secp256k1, SecretKey};
+
+    fn request() -> Event {
+        let secp = Secp256k1::new();
+        let key = SecretKey::from_slice(&[3u8; 32]).unwrap();
+        let pubkey = hex::encode(PublicKey::from_secret_key(&secp, &key).serialize());
+        Event::new(
+            hex::encode(key.secret_bytes()),
+            pubkey,
+            5,
+            vec![],
+            "deletion request".into(),
+        )
+    }
+
+    fn receipt(request: &Event, enclave_key: &SecretKey) -> ErasureReceipt {
+        ErasureReceipt {
+            pubkey: request.pubkey.clone(),
+            request_id: request.id.clone(),
+            request_kind: request.kind,
+            executed_at: request.created_at,
+            event_ids: vec!["ab".repeat(32)],
+            sealed_version: 2,
+            record_hash: "cd".repeat(32),
+            log_head: "cd".repeat(32),
+            relay_pubkey: String::new(),
+            sig: String::new(),
+        }
+        .sign(enclave_key)
+    }
+
+    #[test]
+    fn test_check_receipt() {
+        let enclave_key = SecretKey::from_slice(&[7u8; 32]).unwrap();
+        let transport_pubkey =
+            hex::encode(PublicKey::from_secret_key(&Secp256k1::new(), &enclave_key).serialize());
+        let request = request();
+        let receipt = receipt(&request, &enclave_key);
+        assert!(check_receipt(&receipt, &request, &transport_pubkey).is_ok());
+
+        // signed by some other key than the attested one
+        let other_key = SecretKey::from_slice(&[8u8; 32]).unwrap();
+        let forged = self::receipt(&request, &other_key);
+        assert!(check_receipt(&forged, &request, &transport_pubkey).is_err());
+
+        // a valid receipt for a different request
+        let mut other_request = request.clone();
+        other_request.id = "ef".repeat(32);
+        assert!(check_receipt(&receipt, &other_request, &transport_pubkey).is_err());
+
+        let mut tampered = receipt;
+        tampered.event_ids.clear();
+        assert!(check_receipt(&tampered, &request, &transport_pubkey).is_err());
+    }
+
+    #[test]
+    fn test_store_receipt_appends() {
+        let path = std::env::temp_dir().join(format!("receipts-{}.jsonl", rand::random::<u64>()));
+        let enclave_key = SecretKey::from_slice(&[7u8; 32]).unwrap();
+        let receipt = receipt(&request(), &enclave_key);
+
+        store_receipt(&path, &receipt).unwrap();
+        store_receipt(&path, &receipt).unwrap();
+        let stored = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let lines: Vec<ErasureReceipt> = stored
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines, vec![receipt.clone(), receipt]);
+    }
+}
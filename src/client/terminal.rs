@@ -9,8 +9,10 @@ pub enum Command {
     Help,
     Quit,
     Delete, // Nip-09
+    Export,
     Get,
-    Info, // Nip-11
+    Info,  // Nip-11
+    Admin, // Nip-98 signed
 }
 
 impl fmt::Display for Command {
@@ -22,8 +24,10 @@ impl fmt::Display for Command {
             Command::Help => "help",
             Command::Quit => "quit",
             Command::Delete => "delete",
+            Command::Export => "export",
             Command::Get => "get",
             Command::Info => "info",
+            Command::Admin => "admin",
         };
 
         write!(f, "{}", command)
@@ -59,10 +63,12 @@ impl FromStr for TerminalInput {
             "follow" => Command::Follow,
             "unfollow" => Command::Unfollow,
             "delete" => Command::Delete,
+            "export" => Command::Export,
             "get" => Command::Get,
             "help" => Command::Help,
             "quit" => Command::Quit,
             "info" => Command::Info,
+            "admin" => Command::Admin,
             _ => return Err("invalid command. enter `help` for a list of commands.".to_string()),
         };
 
@@ -71,6 +77,7 @@ impl FromStr for TerminalInput {
         if (command != Command::Help
             && command != Command::Quit
             && command != Command::Delete
+            && command != Command::Export
             && command != Command::Get
             && command != Command::Info)
             && argument.is_none()
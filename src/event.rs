@@ -1,6 +1,9 @@
 use secp256k1::{ecdsa::Signature, Message, PublicKey, Secp256k1, SecretKey};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Event {
@@ -38,6 +41,64 @@ impl Event {
         event
     }
 
+    /// Like `new`, but with a `nonce` tag chosen so the event has proof of
+    /// work of at least `difficulty` (NIP-13). Searches on `threads` threads.
+    pub fn new_with_pow(
+        privkey: String,
+        pubkey: String,
+        kind: usize,
+        tags: Vec<Vec<String>>,
+        content: String,
+        difficulty: usize,
+        threads: usize,
+    ) -> Self {
+        let template = Self {
+            id: "".to_string(),
+            pubkey,
+            created_at: chrono::Utc::now().timestamp() as usize,
+            kind,
+            tags,
+            content,
+            sig: "".to_string(),
+        };
+        let threads = threads.max(1);
+        let found = AtomicBool::new(false);
+        let mined = Mutex::new(None);
+
+        thread::scope(|scope| {
+            for start in 0..threads {
+                let (template, found, mined) = (&template, &found, &mined);
+                scope.spawn(move || {
+                    let mut event = template.clone();
+                    event.tags.push(vec![
+                        "nonce".to_string(),
+                        String::new(),
+                        difficulty.to_string(),
+                    ]);
+                    let nonce = event.tags.len() - 1;
+
+                    // each thread tries every `threads`th nonce
+                    let mut candidate = start;
+                    while !found.load(Ordering::Relaxed) {
+                        event.tags[nonce][1] = candidate.to_string();
+                        event.id = Self::compute_id(&event);
+                        if event.difficulty() >= difficulty {
+                            found.store(true, Ordering::Relaxed);
+                            mined.lock().unwrap().get_or_insert(event);
+                            return;
+                        }
+                        event.id = "".to_string();
+                        candidate += threads;
+                    }
+                });
+            }
+        });
+
+        let mut event: Self = mined.into_inner().unwrap().unwrap();
+        event.sig = Self::sign(&event, privkey);
+        event
+    }
+
     /// Computes the id of the event using the NIP-01 specification
     fn compute_id(event: &Self) -> String {
         let serialized = serde_json::to_string(event).unwrap();
@@ -61,7 +122,9 @@ impl Event {
         hex::encode(signature.serialize_compact())
     }
 
-    /// Verifies the signature of the event; ensures validity of the event on relay-side
+    /// Verifies the id and signature of the event; ensures validity of the event on relay-side.
+    /// The id must be the one computed from the event, or anything keyed or
+    /// measured by it, like duplicates or proof of work, could be forged.
     pub fn verify(&self) -> bool {
         let secp = Secp256k1::new();
 
@@ -70,17 +133,84 @@ impl Event {
         new_event.sig = "".to_string();
         new_event.id = "".to_string();
         let computed_id = Self::compute_id(&new_event);
+        if computed_id != self.id {
+            return false;
+        }
 
         let decoded_id = hex::decode(computed_id).unwrap();
-        let decoded_pubkey = hex::decode(self.pubkey.clone()).unwrap();
-        let decoded_sig = hex::decode(self.sig.clone()).unwrap();
-
         let message = Message::from_digest(decoded_id[..32].try_into().unwrap());
-        let public_key = PublicKey::from_slice(&decoded_pubkey).unwrap();
-        let signature = Signature::from_compact(&decoded_sig).unwrap();
+
+        // malformed keys or signatures simply fail verification
+        let Ok(public_key) = hex::decode(&self.pubkey)
+            .map_err(|_| ())
+            .and_then(|pubkey| PublicKey::from_slice(&pubkey).map_err(|_| ()))
+        else {
+            return false;
+        };
+        let Ok(signature) = hex::decode(&self.sig)
+            .map_err(|_| ())
+            .and_then(|sig| Signature::from_compact(&sig).map_err(|_| ()))
+        else {
+            return false;
+        };
 
         secp.verify_ecdsa(&message, &signature, &public_key).is_ok()
     }
+
+    /// Value of the first tag called `name`
+    pub fn tag(&self, name: &str) -> Option<&str> {
+        self.tags
+            .iter()
+            .find(|tag| tag.first().is_some_and(|tag_name| tag_name == name))
+            .and_then(|tag| tag.get(1))
+            .map(String::as_str)
+    }
+
+    /// Unix time the event expires at (NIP-40), if it has an `expiration` tag
+    pub fn expiration(&self) -> Option<usize> {
+        self.tag("expiration")?.parse().ok()
+    }
+
+    pub fn is_expired(&self, now: usize) -> bool {
+        self.expiration()
+            .is_some_and(|expiration| expiration <= now)
+    }
+
+    /// Whether the event has a `["-"]` tag, so only its author may publish it (NIP-70)
+    pub fn is_protected(&self) -> bool {
+        self.tags.iter().any(|tag| tag.len() == 1 && tag[0] == "-")
+    }
+
+    /// Number of leading zero bits in the id (NIP-13)
+    pub fn difficulty(&self) -> usize {
+        let mut bits = 0;
+        for digit in self.id.chars().map(|c| c.to_digit(16)) {
+            match digit {
+                Some(0) => bits += 4,
+                Some(digit) => return bits + digit.leading_zeros() as usize - 28,
+                None => break,
+            }
+        }
+        bits
+    }
+
+    /// Difficulty the author committed to in the `nonce` tag, if any
+    pub fn committed_target(&self) -> Option<usize> {
+        self.tags
+            .iter()
+            .find(|tag| tag.first().is_some_and(|name| name == "nonce"))
+            .and_then(|tag| tag.get(2))
+            .and_then(|target| target.parse().ok())
+    }
+
+    /// Proof of work the event counts as having: its difficulty, but no
+    /// more than the target it committed to. Without the cap, a lucky id
+    /// mined for a low target would pass for more work than was done.
+    pub fn pow(&self) -> usize {
+        let difficulty = self.difficulty();
+        self.committed_target()
+            .map_or(difficulty, |target| difficulty.min(target))
+    }
 }
 
 #[cfg(test)]
@@ -165,6 +295,42 @@ pub mod test {
         assert!(event.verify());
     }
 
+    #[test]
+    fn test_verify_fails_for_forged_id() {
+        let secp = Secp256k1::new();
+        let privkey = SecretKey::from_slice(&[4u8; 32]).unwrap();
+        let pubkey = PublicKey::from_secret_key(&secp, &privkey);
+
+        let mut event = Event::new(
+            hex::encode(privkey.secret_bytes()),
+            hex::encode(pubkey.serialize()),
+            1,
+            vec![],
+            "content".to_string(),
+        );
+
+        // the signature is still valid, but an all-zero id would pass for
+        // the most proof of work there is
+        event.id = "0".repeat(64);
+        assert_eq!(event.difficulty(), 256);
+        assert!(!event.verify());
+    }
+
+    #[test]
+    fn test_verify_malformed_fails() {
+        let event = Event {
+            id: "id".to_string(),
+            pubkey: "not hex".to_string(),
+            created_at: 0,
+            kind: 0,
+            tags: vec![],
+            content: "content".to_string(),
+            sig: "sig".to_string(),
+        };
+
+        assert!(!event.verify());
+    }
+
     #[test]
     fn test_compute_id() {
         let event_one = Event {
@@ -192,4 +358,94 @@ pub mod test {
 
         assert_eq!(computed_id_one, computed_id_two_identical);
     }
+
+    #[test]
+    fn test_expiration() {
+        let mut event = Event {
+            id: "id".to_string(),
+            pubkey: "pubkey".to_string(),
+            created_at: 0,
+            kind: 1,
+            tags: vec![vec!["p".to_string()]],
+            content: "content".to_string(),
+            sig: "sig".to_string(),
+        };
+        assert_eq!(event.expiration(), None);
+        assert!(!event.is_expired(usize::MAX));
+
+        event
+            .tags
+            .push(vec!["expiration".to_string(), "100".to_string()]);
+        assert_eq!(event.expiration(), Some(100));
+        assert!(!event.is_expired(99));
+        assert!(event.is_expired(100));
+
+        // a malformed expiration is ignored
+        event.tags = vec![vec!["expiration".to_string(), "soon".to_string()]];
+        assert_eq!(event.expiration(), None);
+    }
+
+    #[test]
+    fn test_is_protected() {
+        let mut event = Event {
+            id: "id".to_string(),
+            pubkey: "pubkey".to_string(),
+            created_at: 0,
+            kind: 1,
+            tags: vec![vec!["-".to_string(), "x".to_string()]],
+            content: "content".to_string(),
+            sig: "sig".to_string(),
+        };
+        assert!(!event.is_protected());
+        event.tags.push(vec!["-".to_string()]);
+        assert!(event.is_protected());
+    }
+
+    #[test]
+    fn test_difficulty() {
+        let mut event = Event {
+            id: "000000000e9d97a1ab09fc381030b346cdd7a142ad57e6df0b46dc9bef6c7e2d".to_string(),
+            pubkey: "pubkey".to_string(),
+            created_at: 0,
+            kind: 1,
+            tags: vec![],
+            content: "content".to_string(),
+            sig: "sig".to_string(),
+        };
+        assert_eq!(event.difficulty(), 36);
+        assert_eq!(event.pow(), 36);
+
+        // only the committed target counts
+        event.tags = vec![vec!["nonce".into(), "776797".into(), "20".into()]];
+        assert_eq!(event.committed_target(), Some(20));
+        assert_eq!(event.pow(), 20);
+
+        event.id = "7f".repeat(32);
+        assert_eq!(event.difficulty(), 1);
+        assert_eq!(event.pow(), 1);
+        event.id = "not hex".to_string();
+        assert_eq!(event.difficulty(), 0);
+    }
+
+    #[test]
+    fn test_new_with_pow() {
+        let secp = Secp256k1::new();
+        let privkey = SecretKey::from_slice(&[5u8; 32]).unwrap();
+        let pubkey = PublicKey::from_secret_key(&secp, &privkey);
+
+        let event = Event::new_with_pow(
+            hex::encode(privkey.secret_bytes()),
+            hex::encode(pubkey.serialize()),
+            1,
+            vec![vec!["t".to_string(), "nostr".to_string()]],
+            "content".to_string(),
+            10,
+            4,
+        );
+
+        assert!(event.verify());
+        assert!(event.difficulty() >= 10);
+        assert_eq!(event.pow(), 10);
+        assert_eq!(event.tags[0], vec!["t".to_string(), "nostr".to_string()]);
+    }
 }
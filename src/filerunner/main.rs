@@ -1,50 +1,257 @@
 use axum::{
+    extract::{Path, State},
     http::StatusCode,
     routing::{get, post},
     Router,
 };
-use std::fs;
+use std::fs::{self, File};
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+
+use plugin::Plugin;
+
+mod plugin;
 
 const DB_PATH: &str = "db.blob";
 const SEALDATA_PATH: &str = "sealdata.blob";
-const FILERUNNER_SERVER: &str = "0.0.0.0:5555";
+const DEFAULT_CONFIG_PATH: &str = "relay.toml";
+const DEFAULT_FILERUNNER_SERVER: &str = "0.0.0.0:5555";
+/// How long to wait for the relay's final save after a signal
+const RELAY_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Shutdown handshake with the relay. Signals can't reach the enclave,
+/// so the filerunner catches them and the relay polls `requested`.
+struct Shutdown {
+    requested: AtomicBool,
+    /// Whether the relay's final save succeeded, once it reported back
+    relay_saved: watch::Sender<Option<bool>>,
+}
+
+/// Relay config served to the enclave, set by `--config` or `NOSTRUST_CONFIG`
+static CONFIG_PATH: OnceLock<String> = OnceLock::new();
+
+/// Plugin the relay asks about new events, set by `--write-policy` or `NOSTRUST_WRITE_POLICY`
+static WRITE_POLICY: OnceLock<Mutex<Plugin>> = OnceLock::new();
+
+/// Where to listen, which relay config to serve and which write-policy
+/// plugin to run, if any. Flags win over environment variables.
+fn parse_args() -> Result<(String, String, String), String> {
+    let mut bind = std::env::var("NOSTRUST_FILERUNNER")
+        .unwrap_or_else(|_| DEFAULT_FILERUNNER_SERVER.to_string());
+    let mut config =
+        std::env::var("NOSTRUST_CONFIG").unwrap_or_else(|_| DEFAULT_CONFIG_PATH.to_string());
+    let mut write_policy = std::env::var("NOSTRUST_WRITE_POLICY").unwrap_or_default();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let field = match arg.as_str() {
+            "--bind" => &mut bind,
+            "--config" => &mut config,
+            "--write-policy" => &mut write_policy,
+            _ => return Err(format!("unknown argument `{}`", arg)),
+        };
+        *field = args
+            .next()
+            .ok_or_else(|| format!("{} needs a value", arg))?;
+    }
+
+    Ok((bind, config, write_policy))
+}
+
+// built by hand: `#[tokio::main]` expands to paths starting with `core`,
+// which in this package names its own lib
+fn main() {
+    tokio::runtime::Runtime::new().unwrap().block_on(run());
+}
+
+async fn run() {
+    let (bind, config, plugin) = parse_args().unwrap_or_else(|e| {
+        println!("{}", e);
+        println!(
+            "Usage: filerunner [--bind <address>] [--config <path>] [--write-policy <command>]"
+        );
+        std::process::exit(2);
+    });
+    CONFIG_PATH.set(config).unwrap();
+    if !plugin.is_empty() {
+        WRITE_POLICY.set(Mutex::new(Plugin::new(&plugin))).ok();
+    }
+
+    let shutdown = Arc::new(Shutdown {
+        requested: AtomicBool::new(false),
+        relay_saved: watch::Sender::new(None),
+    });
 
-#[tokio::main]
-async fn main() {
     let app = Router::new()
         .route("/set-db", post(set_db))
         .route("/get-db", get(get_db))
         .route("/set-sealdata", post(set_sealdata))
-        .route("/get-sealdata", get(get_sealdata));
+        .route("/get-sealdata", get(get_sealdata))
+        .route("/get-config", get(get_config))
+        .route("/set-blob/:name", post(set_blob))
+        .route("/get-blob/:name", get(get_blob))
+        .route("/get-shutdown", get(get_shutdown))
+        .route("/set-shutdown", post(set_shutdown))
+        .route("/write-policy", post(write_policy))
+        .with_state(shutdown.clone());
+
+    println!("Running filerunner on {}", bind);
+    let listener = tokio::net::TcpListener::bind(&bind).await.unwrap();
+    let mut relay_saved = shutdown.relay_saved.subscribe();
+    let handshake = shutdown.clone();
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async move {
+            wait_for_signal().await;
+            println!("Asking the relay to save and shut down...");
+            handshake.requested.store(true, Ordering::SeqCst);
 
-    println!("Running filerunner on {}", FILERUNNER_SERVER);
-    let listener = tokio::net::TcpListener::bind(FILERUNNER_SERVER)
+            // keep serving while the relay writes its final seal
+            tokio::select! {
+                _ = relay_saved.wait_for(Option::is_some) => {}
+                _ = tokio::time::sleep(RELAY_SHUTDOWN_TIMEOUT) => {
+                    println!("Relay did not report back in time");
+                }
+                _ = wait_for_signal() => println!("Not waiting for the relay"),
+            }
+        })
         .await
         .unwrap();
-    axum::serve(listener, app).await.unwrap();
+
+    let status = match *shutdown.relay_saved.borrow() {
+        Some(true) => {
+            println!("Relay saved its state, exiting");
+            0
+        }
+        _ => {
+            println!("Relay state may not be saved, exiting");
+            1
+        }
+    };
+    std::process::exit(status);
+}
+
+/// Resolves on SIGINT or SIGTERM
+async fn wait_for_signal() {
+    let mut terminate = signal(SignalKind::terminate()).unwrap();
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+/// Write a file and make sure it reached the disk before answering the relay.
+/// The file is replaced in one step, so a crash leaves the old or the new version.
+fn write_synced(path: &str, body: String) -> std::io::Result<()> {
+    let tmp = format!("{}.tmp", path);
+    let mut file = File::create(&tmp)?;
+    file.write_all(body.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+/// 404 only if the file doesn't exist, so the relay never takes a failed
+/// read for a file that was never saved
+fn read_error(error: std::io::Error) -> StatusCode {
+    if error.kind() == std::io::ErrorKind::NotFound {
+        StatusCode::NOT_FOUND
+    } else {
+        println!("Failed to read file: {}", error);
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
 }
 
 async fn set_db(body: String) -> Result<StatusCode, StatusCode> {
     println!("Setting db");
-    fs::write(DB_PATH, body).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    write_synced(DB_PATH, body).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
     Ok(StatusCode::OK)
 }
 
 async fn get_db() -> Result<String, StatusCode> {
     println!("Getting db");
-    let content = fs::read_to_string(DB_PATH).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let content = fs::read_to_string(DB_PATH).map_err(read_error)?;
     Ok(content)
 }
 
 async fn set_sealdata(body: String) -> Result<StatusCode, StatusCode> {
     println!("Setting sealdata");
-    fs::write(SEALDATA_PATH, body).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    write_synced(SEALDATA_PATH, body).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
     Ok(StatusCode::OK)
 }
 
 async fn get_sealdata() -> Result<String, StatusCode> {
     println!("Getting sealdata");
-    let content =
-        fs::read_to_string(SEALDATA_PATH).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let content = fs::read_to_string(SEALDATA_PATH).map_err(read_error)?;
+    Ok(content)
+}
+
+async fn get_config() -> Result<String, StatusCode> {
+    println!("Getting config");
+    let path = CONFIG_PATH.get().unwrap();
+    let content = fs::read_to_string(path).map_err(read_error)?;
     Ok(content)
 }
+
+/// File holding the blob `name`, or `None` if the name could escape the working directory
+fn blob_path(name: &str) -> Option<String> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    valid.then(|| format!("{}.blob", name))
+}
+
+async fn set_blob(Path(name): Path<String>, body: String) -> Result<StatusCode, StatusCode> {
+    println!("Setting blob {}", name);
+    let path = blob_path(&name).ok_or(StatusCode::BAD_REQUEST)?;
+    write_synced(&path, body).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(StatusCode::OK)
+}
+
+async fn get_blob(Path(name): Path<String>) -> Result<String, StatusCode> {
+    println!("Getting blob {}", name);
+    let path = blob_path(&name).ok_or(StatusCode::BAD_REQUEST)?;
+    let content = fs::read_to_string(path).map_err(read_error)?;
+    Ok(content)
+}
+
+/// 200 once the relay should shut down
+async fn get_shutdown(State(shutdown): State<Arc<Shutdown>>) -> StatusCode {
+    if shutdown.requested.load(Ordering::SeqCst) {
+        StatusCode::OK
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+/// The relay reports whether its final save succeeded
+async fn set_shutdown(State(shutdown): State<Arc<Shutdown>>, body: String) -> StatusCode {
+    let saved = body.trim() == "saved";
+    println!("Relay shut down, state saved: {}", saved);
+    shutdown.relay_saved.send_replace(Some(saved));
+    StatusCode::OK
+}
+
+/// Pass an event from the relay to the write-policy plugin, one line
+/// each way, and return the plugin's answer
+async fn write_policy(body: String) -> Result<String, StatusCode> {
+    let plugin = WRITE_POLICY.get().ok_or(StatusCode::NOT_FOUND)?;
+    if body.contains('\n') {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    tokio::task::spawn_blocking(move || {
+        plugin
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .ask(&body)
+    })
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .map_err(|e| {
+        println!("Write policy plugin failed: {}", e);
+        StatusCode::BAD_GATEWAY
+    })
+}
@@ -0,0 +1,207 @@
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+
+/// How long the plugin may take to answer an event. Relay workers wait
+/// in line for the plugin, so one that hangs would stall them all.
+const PLUGIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Write-policy plugin run for the relay, which can't start processes
+/// inside the enclave. Like a strfry plugin it gets one JSON line per
+/// event on stdin and answers each with one JSON line on stdout.
+/// If it exits or doesn't answer in time, it is started again for the
+/// next event.
+pub struct Plugin {
+    command: String,
+    timeout: Duration,
+    process: Option<Running>,
+}
+
+/// A started plugin. Its pipes are used from a thread of their own,
+/// so a plugin that stops reading or answering can't block the caller.
+struct Running {
+    child: Child,
+    lines: Sender<String>,
+    answers: Receiver<std::io::Result<String>>,
+}
+
+impl Plugin {
+    pub fn new(command: &str) -> Self {
+        Self {
+            command: command.to_string(),
+            timeout: PLUGIN_TIMEOUT,
+            process: None,
+        }
+    }
+
+    /// Send `line` to the plugin and return its answer
+    pub fn ask(&mut self, line: &str) -> std::io::Result<String> {
+        let running = match &mut self.process {
+            Some(running) => running,
+            process => process.insert(self::spawn(&self.command)?),
+        };
+
+        let answer = match running.lines.send(line.to_string()) {
+            Ok(()) => match running.answers.recv_timeout(self.timeout) {
+                Ok(answer) => answer,
+                Err(RecvTimeoutError::Timeout) => Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "plugin did not answer in time",
+                )),
+                Err(RecvTimeoutError::Disconnected) => Err(stopped()),
+            },
+            Err(_) => Err(stopped()),
+        };
+        if answer.is_err() {
+            self.stop();
+        }
+        answer
+    }
+
+    fn stop(&mut self) {
+        if let Some(mut running) = self.process.take() {
+            // the exchange thread ends once the pipes close
+            let _ = running.child.kill();
+            let _ = running.child.wait();
+        }
+    }
+}
+
+impl Drop for Plugin {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn stopped() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::BrokenPipe, "plugin stopped")
+}
+
+fn spawn(command: &str) -> std::io::Result<Running> {
+    println!("Starting write policy plugin {}", command);
+    let mut child = Command::new(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+
+    let (lines, line_receiver) = mpsc::channel::<String>();
+    let (answer_sender, answers) = mpsc::channel();
+    thread::spawn(move || {
+        for line in line_receiver {
+            let answer = exchange(&mut stdin, &mut stdout, &line);
+            let failed = answer.is_err();
+            if answer_sender.send(answer).is_err() || failed {
+                break;
+            }
+        }
+    });
+
+    Ok(Running {
+        child,
+        lines,
+        answers,
+    })
+}
+
+fn exchange(
+    stdin: &mut ChildStdin,
+    stdout: &mut BufReader<ChildStdout>,
+    line: &str,
+) -> std::io::Result<String> {
+    writeln!(stdin, "{}", line)?;
+    stdin.flush()?;
+
+    let mut answer = String::new();
+    if stdout.read_line(&mut answer)? == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            "plugin exited without answering",
+        ));
+    }
+    Ok(answer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    /// Write an executable shell script and return its path
+    fn script(name: &str, body: &str) -> String {
+        let path = std::env::temp_dir().join(format!("{}-{}.sh", name, std::process::id()));
+        std::fs::write(&path, format!("#!/bin/sh\n{}", body)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn test_plugin_answers_each_line() {
+        let path = script(
+            "spam-filter",
+            r#"while read -r line; do
+  id=$(echo "$line" | sed 's/.*"id":"\([^"]*\)".*/\1/')
+  case "$line" in
+    *spam*) echo "{\"id\":\"$id\",\"action\":\"shadowReject\",\"msg\":\"spam\"}" ;;
+    *) echo "{\"id\":\"$id\",\"action\":\"accept\"}" ;;
+  esac
+done
+"#,
+        );
+        let mut plugin = Plugin::new(&path);
+
+        let answer = plugin
+            .ask(r#"{"type":"new","event":{"id":"ab","content":"hello"}}"#)
+            .unwrap();
+        assert_eq!(answer, "{\"id\":\"ab\",\"action\":\"accept\"}\n");
+        let answer = plugin
+            .ask(r#"{"type":"new","event":{"id":"cd","content":"spam"}}"#)
+            .unwrap();
+        assert!(answer.contains("shadowReject"));
+        drop(plugin);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_plugin_is_restarted_after_exiting() {
+        // answers a single line, then exits
+        let path = script("one-shot", "read -r line\necho \"$line\"\n");
+        let mut plugin = Plugin::new(&path);
+
+        assert_eq!(plugin.ask("first").unwrap(), "first\n");
+        assert!(plugin.ask("second").is_err());
+        assert_eq!(plugin.ask("third").unwrap(), "third\n");
+        drop(plugin);
+        std::fs::remove_file(path).unwrap();
+
+        assert!(Plugin::new("/nonexistent/plugin").ask("line").is_err());
+    }
+
+    #[test]
+    fn test_plugin_is_restarted_after_not_answering() {
+        // answers with its process id, except for lines it hangs on
+        let path = script(
+            "hanging",
+            r#"while read -r line; do
+  case "$line" in
+    hang) ;;
+    *) echo "$$" ;;
+  esac
+done
+"#,
+        );
+        let mut plugin = Plugin::new(&path);
+        plugin.timeout = Duration::from_millis(500);
+
+        let first = plugin.ask("first").unwrap();
+        let hung = plugin.ask("hang").unwrap_err();
+        assert_eq!(hung.kind(), std::io::ErrorKind::TimedOut);
+        let restarted = plugin.ask("second").unwrap();
+        assert_ne!(first, restarted);
+        drop(plugin);
+        std::fs::remove_file(path).unwrap();
+    }
+}
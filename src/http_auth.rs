@@ -0,0 +1,190 @@
+use crate::event::Event;
+use anyhow::{anyhow, bail, Result};
+use base64::{engine::general_purpose, Engine};
+use sha2::{Digest, Sha256};
+
+/// Event kind of an HTTP auth event (NIP-98)
+pub const HTTP_AUTH_KIND: usize = 27235;
+/// How far an HTTP auth event's `created_at` may be from the server's clock
+pub const HTTP_AUTH_WINDOW_SECS: usize = 60;
+
+/// Create a signed HTTP auth event (NIP-98) for a request to `url`
+pub fn http_auth_event(
+    privkey: String,
+    pubkey: String,
+    url: &str,
+    method: &str,
+    body: &[u8],
+) -> Event {
+    let mut tags = vec![
+        vec!["u".to_string(), url.to_string()],
+        vec!["method".to_string(), method.to_uppercase()],
+    ];
+    if !body.is_empty() {
+        tags.push(vec![
+            "payload".to_string(),
+            hex::encode(Sha256::digest(body)),
+        ]);
+    }
+
+    Event::new(privkey, pubkey, HTTP_AUTH_KIND, tags, "".to_string())
+}
+
+/// Value of the `Authorization` header carrying `event`
+pub fn authorization_header(event: &Event) -> String {
+    let serialized = serde_json::to_string(event).unwrap();
+    format!("Nostr {}", general_purpose::STANDARD.encode(serialized))
+}
+
+/// First value of the tag named `name`
+fn tag_value<'a>(event: &'a Event, name: &str) -> Option<&'a str> {
+    event
+        .tags
+        .iter()
+        .find(|tag| tag.first().map(String::as_str) == Some(name))
+        .and_then(|tag| tag.get(1))
+        .map(String::as_str)
+}
+
+/// Path part of an absolute or relative URL
+fn url_path(url: &str) -> &str {
+    match url.split_once("://") {
+        Some((_, rest)) => rest.find('/').map_or("/", |i| &rest[i..]),
+        None => url,
+    }
+}
+
+/// Checks an `Authorization` header against the request it came with.
+/// Returns the verified auth event, whose `pubkey` is the requester.
+pub fn verify_authorization(
+    header: &str,
+    path: &str,
+    method: &str,
+    body: &[u8],
+    now: usize,
+) -> Result<Event> {
+    let encoded = header
+        .strip_prefix("Nostr ")
+        .ok_or_else(|| anyhow!("authorization scheme must be `Nostr`"))?;
+    let event: Event = serde_json::from_slice(&general_purpose::STANDARD.decode(encoded.trim())?)?;
+
+    if event.kind != HTTP_AUTH_KIND {
+        bail!("auth event must be of kind {}", HTTP_AUTH_KIND);
+    }
+    if event.created_at.abs_diff(now) > HTTP_AUTH_WINDOW_SECS {
+        bail!("auth event is too old or too far in the future");
+    }
+
+    let url = tag_value(&event, "u").ok_or_else(|| anyhow!("auth event has no `u` tag"))?;
+    if url_path(url) != path {
+        bail!("auth event is for a different URL");
+    }
+    let auth_method =
+        tag_value(&event, "method").ok_or_else(|| anyhow!("auth event has no `method` tag"))?;
+    if !auth_method.eq_ignore_ascii_case(method) {
+        bail!("auth event is for a different method");
+    }
+    if !body.is_empty() {
+        let payload = tag_value(&event, "payload")
+            .ok_or_else(|| anyhow!("auth event has no `payload` tag"))?;
+        if payload != hex::encode(Sha256::digest(body)) {
+            bail!("auth event is for a different body");
+        }
+    }
+
+    if !event.verify() {
+        bail!("auth event failed verification");
+    }
+
+    Ok(event)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secp256k1::{PublicKey, Secp256k1, SecretKey};
+
+    fn keys() -> (String, String) {
+        let secp = Secp256k1::new();
+        let privkey = SecretKey::from_slice(&[0x11; 32]).unwrap();
+        let pubkey = PublicKey::from_secret_key(&secp, &privkey);
+        (
+            hex::encode(privkey.secret_bytes()),
+            hex::encode(pubkey.serialize()),
+        )
+    }
+
+    #[test]
+    fn test_verify_authorization_pass() {
+        let (privkey, pubkey) = keys();
+        let event = http_auth_event(
+            privkey,
+            pubkey.clone(),
+            "http://localhost:8080/admin/stats",
+            "post",
+            b"",
+        );
+        let header = authorization_header(&event);
+
+        let verified =
+            verify_authorization(&header, "/admin/stats", "POST", b"", event.created_at).unwrap();
+        assert_eq!(verified.pubkey, pubkey);
+    }
+
+    #[test]
+    fn test_verify_authorization_fail_url() {
+        let (privkey, pubkey) = keys();
+        let event = http_auth_event(privkey, pubkey, "/admin/stats", "POST", b"");
+        let header = authorization_header(&event);
+
+        assert!(
+            verify_authorization(&header, "/admin/shutdown", "POST", b"", event.created_at)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_verify_authorization_fail_expired() {
+        let (privkey, pubkey) = keys();
+        let event = http_auth_event(privkey, pubkey, "/admin/stats", "POST", b"");
+        let header = authorization_header(&event);
+        let later = event.created_at + HTTP_AUTH_WINDOW_SECS + 1;
+
+        assert!(verify_authorization(&header, "/admin/stats", "POST", b"", later).is_err());
+    }
+
+    #[test]
+    fn test_verify_authorization_fail_payload() {
+        let (privkey, pubkey) = keys();
+        let event = http_auth_event(privkey, pubkey, "/admin/stats", "POST", b"{}");
+        let header = authorization_header(&event);
+
+        assert!(verify_authorization(
+            &header,
+            "/admin/stats",
+            "POST",
+            b"{\"x\":1}",
+            event.created_at
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_verify_authorization_fail_tampered() {
+        let (privkey, pubkey) = keys();
+        let mut event = http_auth_event(privkey, pubkey, "/admin/stats", "POST", b"");
+        event.tags[0][1] = "/admin/shutdown".to_string();
+        let header = authorization_header(&event);
+
+        assert!(
+            verify_authorization(&header, "/admin/shutdown", "POST", b"", event.created_at)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_verify_authorization_fail_garbage() {
+        assert!(verify_authorization("Nostr !!!", "/admin/stats", "POST", b"", 0).is_err());
+        assert!(verify_authorization("Basic abc", "/admin/stats", "POST", b"", 0).is_err());
+    }
+}
@@ -1,3 +1,4 @@
+use crate::attestation::Attestation;
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
@@ -9,8 +10,183 @@ pub struct Info {
     pub banner: Option<String>,
     pub icon: Option<String>,
     pub contact: Option<String>,
+    /// Hex public key of the relay operator
+    pub pubkey: Option<String>,
     pub supported_nips: Vec<usize>,
     pub software: String,
     pub version: String,
-    pub attestation: [u8; 32],
-}
\ No newline at end of file
+    pub limitation: Option<Limitation>,
+    #[serde(default)]
+    pub retention: Vec<Retention>,
+    /// ISO 3166-1 alpha-2 codes of the countries whose laws may apply
+    #[serde(default)]
+    pub relay_countries: Vec<String>,
+    /// IETF language tags of the content the relay is meant for
+    #[serde(default)]
+    pub language_tags: Vec<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub posting_policy: Option<String>,
+    pub payments_url: Option<String>,
+    pub fees: Option<Fees>,
+    /// Extension: proof that the relay runs in a genuine enclave
+    pub attestation: Option<Attestation>,
+    /// Extension: new events leave the enclave for a write-policy plugin
+    /// run by the host, which sees them in the clear
+    #[serde(default)]
+    pub write_policy_plugin: bool,
+}
+
+/// Limits the relay enforces on what clients send.
+/// Limits the relay doesn't have are left out.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct Limitation {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_message_length: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_subscriptions: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_filters: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_limit: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_subid_length: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_event_tags: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_content_length: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_pow_difficulty: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth_required: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payment_required: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub restricted_writes: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_at_lower_limit: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_at_upper_limit: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_limit: Option<usize>,
+}
+
+/// What the relay charges for
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct Fees {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub admission: Vec<Fee>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub subscription: Vec<Fee>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub publication: Vec<Fee>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct Fee {
+    pub amount: u64,
+    /// Such as `msats`
+    pub unit: String,
+    /// Seconds a subscription lasts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub period: Option<u64>,
+    /// Kinds a publication fee applies to
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub kinds: Vec<usize>,
+}
+
+/// A single kind, or an inclusive range of kinds
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(untagged)]
+pub enum Kinds {
+    Kind(usize),
+    Range([usize; 2]),
+}
+
+impl Kinds {
+    pub fn contains(&self, kind: usize) -> bool {
+        match *self {
+            Kinds::Kind(k) => k == kind,
+            Kinds::Range([start, end]) => (start..=end).contains(&kind),
+        }
+    }
+}
+
+/// How long, or how many, events of some kinds are kept.
+/// No kinds means the rule applies to every kind.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct Retention {
+    #[serde(default)]
+    pub kinds: Vec<Kinds>,
+    /// Seconds after `created_at` an event is kept for
+    pub time: Option<usize>,
+    /// Number of events kept per pubkey, newest first
+    pub count: Option<usize>,
+}
+
+impl Retention {
+    pub fn applies_to(&self, kind: usize) -> bool {
+        self.kinds.is_empty() || self.kinds.iter().any(|kinds| kinds.contains(kind))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retention_kinds() {
+        let retention: Retention =
+            serde_json::from_str(r#"{"kinds": [0, 1, [5, 7]], "time": 3600}"#).unwrap();
+
+        assert!(retention.applies_to(1));
+        assert!(retention.applies_to(6));
+        assert!(!retention.applies_to(4));
+        assert_eq!(retention.kinds[2], Kinds::Range([5, 7]));
+        assert!(Retention::default().applies_to(30023));
+    }
+
+    #[test]
+    fn test_parse_nip11_document() {
+        let document = r#"{
+            "name": "JellyFish",
+            "description": "Stay Immortal!",
+            "banner": null,
+            "icon": null,
+            "contact": "hi@dezh.tech",
+            "pubkey": "bf2bee5281149c7c350f5d12ae32f514c7864ff10805182f4178538c2c421007",
+            "supported_nips": [1, 9, 11, 13, 17, 40, 42, 59, 62, 70],
+            "software": "https://github.com/dezh-tech/immortal",
+            "version": "immortal - 0.0.9",
+            "limitation": {
+                "max_message_length": 70000,
+                "max_subscriptions": 350,
+                "auth_required": false,
+                "payment_required": true,
+                "restricted_writes": true
+            },
+            "relay_countries": ["*"],
+            "language_tags": ["*"],
+            "tags": [],
+            "posting_policy": "https://jellyfish.land/tos.txt",
+            "payments_url": "https://jellyfish.land/relay",
+            "fees": {
+                "subscription": [{ "amount": 3000, "unit": "sats", "period": 2628003 }]
+            }
+        }"#;
+
+        let info: Info = serde_json::from_str(document).unwrap();
+        let limitation = info.limitation.as_ref().unwrap();
+        assert_eq!(limitation.max_subscriptions, Some(350));
+        assert_eq!(limitation.payment_required, Some(true));
+        assert_eq!(
+            info.fees.as_ref().unwrap().subscription[0].period,
+            Some(2628003)
+        );
+        assert!(info.attestation.is_none());
+
+        // limits the relay doesn't have are left out
+        let serialized = serde_json::to_value(&info).unwrap();
+        assert!(serialized["limitation"].get("max_limit").is_none());
+    }
+}
@@ -1,4 +1,8 @@
+pub mod attestation;
+pub mod channel;
 pub mod event;
 pub mod filter;
+pub mod http_auth;
 pub mod message;
-pub mod info;
\ No newline at end of file
+pub mod info;
+pub mod receipt;
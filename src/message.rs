@@ -1,9 +1,16 @@
 // use core::event::Event;
 // use core::filter::Filter;
+use crate::channel::EncryptedMessage;
 use crate::event::Event;
 use crate::filter::Filter;
 use crate::info::Info;
+use crate::receipt::ErasureReceipt;
+use anyhow::{bail, Result};
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// Kind of NIP-42 auth events
+pub const AUTH_KIND: usize = 22242;
 
 // #[allow(dead_code)]
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -11,14 +18,110 @@ pub enum ClientMessage {
     Event(Event),
     Req(String, Vec<Filter>),
     Close(String, Vec<Filter>),
-    Info,
+    /// Request the relay info, with a fresh hex nonce for the attestation quote
+    Info(String),
     Get(String),
+    /// Request the receipt for an erasure, by the id of the deletion
+    /// or vanish event that asked for it
+    Receipt(String),
+    /// Ask for a challenge to authenticate the encrypted session with (NIP-42)
+    Challenge,
+    /// Signed kind 22242 event answering the session's challenge (NIP-42)
+    Auth(Event),
+    /// Request a copy of everything stored for a user (GDPR access and portability)
+    Export(String),
+    /// Any other message, encrypted for the relay enclave
+    Encrypted(EncryptedMessage),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum RelayMessage {
     Events(Vec<Event>),
-    Info(Info),
+    Info(Box<Info>),
+    /// Whether an event was accepted (NIP-01): event id, accepted, message
+    Ok(String, bool, String),
+    /// A subscription was refused or ended by the relay (NIP-01):
+    /// subscription id, message
+    Closed(String, String),
+    /// Human readable error about a request (NIP-01)
+    Notice(String),
+    /// Signed proof that an erasure was executed
+    Receipt(Box<ErasureReceipt>),
+    /// Challenge to sign to authenticate (NIP-42)
+    Auth(String),
+    /// Everything stored for a user
+    Export(Box<DataExport>),
+}
+
+/// Copy of the data a relay stores for one user
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct DataExport {
+    pub pubkey: String,
+    /// The user's stored events, oldest first
+    pub events: Vec<Event>,
+    /// Pubkeys the user follows
+    pub follows: Vec<String>,
+}
+
+impl RelayMessage {
+    /// Rejection of the event `id`, with a NIP-01 machine readable prefix
+    /// such as `invalid` or `blocked`
+    pub fn rejected(id: &str, prefix: &str, reason: &str) -> Self {
+        RelayMessage::Ok(id.to_string(), false, format!("{}: {}", prefix, reason))
+    }
+
+    /// Serialize as a response body: events and info as they are,
+    /// `OK`, `CLOSED`, `NOTICE` and `AUTH` as NIP-01 arrays, receipts and exports tagged the same way
+    pub fn to_body(&self) -> Vec<u8> {
+        let value = match self {
+            RelayMessage::Events(events) => json!(events),
+            RelayMessage::Info(info) => json!(info),
+            RelayMessage::Ok(id, accepted, message) => json!(["OK", id, accepted, message]),
+            RelayMessage::Closed(id, message) => json!(["CLOSED", id, message]),
+            RelayMessage::Notice(message) => json!(["NOTICE", message]),
+            RelayMessage::Receipt(receipt) => json!(["RECEIPT", receipt]),
+            RelayMessage::Auth(challenge) => json!(["AUTH", challenge]),
+            RelayMessage::Export(export) => json!(["EXPORT", export]),
+        };
+        serde_json::to_vec(&value).unwrap_or_default()
+    }
+
+    /// Parse a response body written by `to_body`
+    pub fn from_body(body: &[u8]) -> Result<Self> {
+        let value: Value = serde_json::from_slice(body)?;
+        let tag = value.get(0).and_then(Value::as_str);
+        let message = match (tag, &value) {
+            (Some("OK"), _) => {
+                let (_, id, accepted, message) =
+                    serde_json::from_value::<(String, String, bool, String)>(value)?;
+                RelayMessage::Ok(id, accepted, message)
+            }
+            (Some("CLOSED"), _) => {
+                let (_, id, message) = serde_json::from_value::<(String, String, String)>(value)?;
+                RelayMessage::Closed(id, message)
+            }
+            (Some("RECEIPT"), _) => {
+                let (_, receipt) = serde_json::from_value::<(String, ErasureReceipt)>(value)?;
+                RelayMessage::Receipt(Box::new(receipt))
+            }
+            (Some("NOTICE"), _) => {
+                let (_, message) = serde_json::from_value::<(String, String)>(value)?;
+                RelayMessage::Notice(message)
+            }
+            (Some("AUTH"), _) => {
+                let (_, challenge) = serde_json::from_value::<(String, String)>(value)?;
+                RelayMessage::Auth(challenge)
+            }
+            (Some("EXPORT"), _) => {
+                let (_, export) = serde_json::from_value::<(String, DataExport)>(value)?;
+                RelayMessage::Export(Box::new(export))
+            }
+            (_, Value::Array(_)) => RelayMessage::Events(serde_json::from_value(value)?),
+            (_, Value::Object(_)) => RelayMessage::Info(Box::new(serde_json::from_value(value)?)),
+            _ => bail!("unexpected response"),
+        };
+        Ok(message)
+    }
 }
 
 // testing to see what the messages look like
@@ -43,4 +146,46 @@ mod tests {
 
         assert_eq!(deserialized, original);
     }
+
+    #[test]
+    fn test_relay_message_body() {
+        let messages = [
+            RelayMessage::Events(vec![]),
+            RelayMessage::Info(Box::default()),
+            RelayMessage::Ok("id".to_string(), true, "".to_string()),
+            RelayMessage::rejected("id", "invalid", "bad signature"),
+            RelayMessage::Closed("sub".to_string(), "rate-limited: slow down".to_string()),
+            RelayMessage::Notice("error: bad request".to_string()),
+            RelayMessage::Auth("challenge".to_string()),
+            RelayMessage::Export(Box::new(DataExport {
+                pubkey: "pubkey".to_string(),
+                events: vec![],
+                follows: vec!["other".to_string()],
+            })),
+            RelayMessage::Receipt(Box::new(ErasureReceipt {
+                pubkey: "pubkey".to_string(),
+                request_id: "id".to_string(),
+                request_kind: 5,
+                executed_at: 0,
+                event_ids: vec!["cd".repeat(32)],
+                sealed_version: 1,
+                record_hash: "hash".to_string(),
+                log_head: "hash".to_string(),
+                relay_pubkey: "relay".to_string(),
+                sig: "sig".to_string(),
+            })),
+        ];
+        for message in messages {
+            assert_eq!(
+                RelayMessage::from_body(&message.to_body()).unwrap(),
+                message
+            );
+        }
+
+        assert_eq!(
+            RelayMessage::rejected("id", "blocked", "banned").to_body(),
+            br#"["OK","id",false,"blocked: banned"]"#
+        );
+        assert!(RelayMessage::from_body(b"OK").is_err());
+    }
 }
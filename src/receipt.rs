@@ -0,0 +1,112 @@
+use secp256k1::{ecdsa::Signature, Message, PublicKey, Secp256k1, SecretKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Proof from the relay enclave that a user's erasure request was executed.
+///
+/// Signed with the enclave's transport key, whose attestation binds it to
+/// the measured relay code. The hashes point into the relay's audit log.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ErasureReceipt {
+    /// Hex public key of the user whose data was erased
+    pub pubkey: String,
+    /// Id of the deletion (NIP-09) or vanish (NIP-62) request
+    pub request_id: String,
+    pub request_kind: usize,
+    /// Unix time the erasure was executed at
+    pub executed_at: usize,
+    /// Ids of the events erased
+    pub event_ids: Vec<String>,
+    /// First sealed state version of the relay that no longer holds the
+    /// erased events
+    pub sealed_version: u64,
+    /// Hash of the audit log record of the erasure
+    pub record_hash: String,
+    /// Hash of the newest audit log record when the receipt was issued
+    pub log_head: String,
+    /// Hex public key of the enclave that signed the receipt
+    pub relay_pubkey: String,
+    pub sig: String,
+}
+
+impl ErasureReceipt {
+    /// Hash of everything but the signature
+    fn digest(&self) -> [u8; 32] {
+        let unsigned = ErasureReceipt {
+            sig: String::new(),
+            ..self.clone()
+        };
+        Sha256::digest(serde_json::to_vec(&unsigned).unwrap()).into()
+    }
+
+    /// Sign the receipt with `key`, which becomes its `relay_pubkey`
+    pub fn sign(mut self, key: &SecretKey) -> Self {
+        let secp = Secp256k1::new();
+        self.relay_pubkey = hex::encode(PublicKey::from_secret_key(&secp, key).serialize());
+        let message = Message::from_digest(self.digest());
+        self.sig = hex::encode(secp.sign_ecdsa(&message, key).serialize_compact());
+        self
+    }
+
+    /// Whether the signature is valid for `relay_pubkey`. Whether that key
+    /// belongs to an attested enclave has to be checked separately.
+    pub fn verify(&self) -> bool {
+        let Ok(public_key) = hex::decode(&self.relay_pubkey)
+            .map_err(|_| ())
+            .and_then(|pubkey| PublicKey::from_slice(&pubkey).map_err(|_| ()))
+        else {
+            return false;
+        };
+        let Ok(signature) = hex::decode(&self.sig)
+            .map_err(|_| ())
+            .and_then(|sig| Signature::from_compact(&sig).map_err(|_| ()))
+        else {
+            return false;
+        };
+
+        let message = Message::from_digest(self.digest());
+        Secp256k1::new()
+            .verify_ecdsa(&message, &signature, &public_key)
+            .is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn receipt() -> ErasureReceipt {
+        ErasureReceipt {
+            pubkey: "02".repeat(33),
+            request_id: "ab".repeat(32),
+            request_kind: 5,
+            executed_at: 1000,
+            event_ids: vec!["01".repeat(32), "02".repeat(32)],
+            sealed_version: 4,
+            record_hash: "cd".repeat(32),
+            log_head: "ef".repeat(32),
+            relay_pubkey: String::new(),
+            sig: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let key = SecretKey::from_slice(&[9u8; 32]).unwrap();
+        let receipt = receipt().sign(&key);
+        assert!(receipt.verify());
+
+        let mut tampered = receipt.clone();
+        tampered.event_ids.pop();
+        assert!(!tampered.verify());
+
+        // a different key can't claim the signature
+        let other = SecretKey::from_slice(&[8u8; 32]).unwrap();
+        let mut forged = receipt;
+        forged.relay_pubkey =
+            hex::encode(PublicKey::from_secret_key(&Secp256k1::new(), &other).serialize());
+        assert!(!forged.verify());
+
+        assert!(!self::receipt().verify());
+    }
+}
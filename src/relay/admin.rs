@@ -0,0 +1,318 @@
+use anyhow::{anyhow, bail, Result};
+use core::http_auth::{self, HTTP_AUTH_WINDOW_SECS};
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::str::FromStr;
+use tiny_http::{Request, Response};
+
+use crate::audit::AuditAction;
+use crate::config::{AdminConfig, RelayConfig};
+use crate::db::DataHolder;
+use crate::server::Relay;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AdminAction {
+    /// SEAL the database and send it to the filerunner
+    Save,
+    /// Stop the relay once pending requests are handled, saving first
+    Shutdown,
+    /// Load the database from the filerunner (UNSEAL)
+    Reload,
+    /// Report counters about the stored data
+    Stats,
+    /// Report how many records of each kind the audit log holds, and its head
+    Audit,
+    /// Fetch the relay config from the filerunner again
+    ReloadConfig,
+}
+
+impl FromStr for AdminAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "save" => Ok(AdminAction::Save),
+            "shutdown" => Ok(AdminAction::Shutdown),
+            "reload" => Ok(AdminAction::Reload),
+            "stats" => Ok(AdminAction::Stats),
+            "audit" => Ok(AdminAction::Audit),
+            "reload-config" => Ok(AdminAction::ReloadConfig),
+            _ => Err(format!("unknown admin action `{}`", s)),
+        }
+    }
+}
+
+/// Hex public keys allowed to sign admin requests, separated by commas.
+/// They are fixed when the relay is built, and so part of the enclave's
+/// measurement: the host can't make itself an admin through the config.
+const ADMIN_PUBKEYS: Option<&str> = option_env!("NOSTRUST_ADMIN_PUBKEYS");
+
+/// The admin keys this relay was built with
+pub fn built_in_pubkeys() -> Vec<String> {
+    ADMIN_PUBKEYS
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|pubkey| !pubkey.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Authenticates admin requests. They are POSTed to the configured prefix
+/// followed by the action, signed with a NIP-98 `Authorization` header by
+/// one of the admin keys.
+pub struct Admin {
+    pubkeys: Vec<String>,
+    path_prefix: String,
+    /// Auth events already used, with their `created_at`, so a captured
+    /// request can't be replayed while it is still fresh
+    seen: HashMap<String, usize>,
+}
+
+impl Admin {
+    pub fn new(pubkeys: Vec<String>, config: &AdminConfig) -> Self {
+        Self {
+            pubkeys,
+            path_prefix: config.path_prefix.clone(),
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Take the path prefix from a reloaded config. The keys stay, and so
+    /// do the auth events already used.
+    pub fn set_config(&mut self, config: &AdminConfig) {
+        self.path_prefix = config.path_prefix.clone();
+    }
+
+    /// The action requested by an admin path, if `path` is one
+    pub fn action<'a>(&self, path: &'a str) -> Option<&'a str> {
+        path.strip_prefix(&self.path_prefix)
+    }
+
+    /// Check a request's NIP-98 authorization. Returns the admin's pubkey.
+    pub fn authorize(
+        &mut self,
+        authorization: Option<&str>,
+        path: &str,
+        method: &str,
+        body: &[u8],
+        now: usize,
+    ) -> Result<String> {
+        let header = authorization.ok_or_else(|| anyhow!("missing Authorization header"))?;
+        let event = http_auth::verify_authorization(header, path, method, body, now)?;
+
+        if !self.pubkeys.contains(&event.pubkey) {
+            bail!("{} is not an admin", event.pubkey);
+        }
+
+        // forget auth events that can no longer pass the time check
+        self.seen
+            .retain(|_, created_at| created_at.abs_diff(now) <= HTTP_AUTH_WINDOW_SECS);
+        if self.seen.insert(event.id, event.created_at).is_some() {
+            bail!("auth event was already used");
+        }
+
+        Ok(event.pubkey)
+    }
+}
+
+/// Check that an admin signed a request with the already read `body`.
+/// Returns the admin's pubkey; rejections are logged.
+pub fn authorize_request(req: &Request, body: &[u8], admin: &mut Admin) -> Option<String> {
+    let path = req.url().to_string();
+    let method = req.method().to_string();
+    let authorization = req
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Authorization"))
+        .map(|header| header.value.to_string());
+
+    let now = chrono::Utc::now().timestamp() as usize;
+    match admin.authorize(authorization.as_deref(), &path, &method, body, now) {
+        Ok(pubkey) => Some(pubkey),
+        Err(e) => {
+            println!("Admin: rejected {} {}: {}", method, path, e);
+            None
+        }
+    }
+}
+
+/// Authorize and run an admin request with the already read `body`.
+/// Every attempt is logged.
+pub fn handle(req: &Request, body: &[u8], name: &str, relay: &Relay) -> Response<Cursor<Vec<u8>>> {
+    let Some(pubkey) = authorize_request(req, body, &mut relay.admin()) else {
+        return Response::from_string("unauthorized").with_status_code(401);
+    };
+
+    let action = match AdminAction::from_str(name) {
+        Ok(action) => action,
+        Err(e) => {
+            println!("Admin: {} sent {}", pubkey, e);
+            return Response::from_string(e).with_status_code(404);
+        }
+    };
+    println!("Admin: {} requested {:?}", pubkey, action);
+    let record = AuditAction::Admin {
+        pubkey,
+        action: name.to_string(),
+    };
+    let now = chrono::Utc::now().timestamp() as usize;
+    // a reload replaces the log, so it is recorded in the new one
+    if action != AdminAction::Reload {
+        relay.db_mut().audit(now, record.clone());
+    }
+
+    match action {
+        AdminAction::Save => match relay.save() {
+            Ok(()) => Response::from_string("OK").with_status_code(200),
+            Err(e) => {
+                println!("Admin: failed to save db: {}", e);
+                Response::from_string("failed to save db").with_status_code(500)
+            }
+        },
+        AdminAction::Shutdown => {
+            // the relay saves and exits after answering pending requests
+            relay.shutdown.request();
+            Response::from_string("shutting down").with_status_code(202)
+        }
+        AdminAction::Reload => {
+            // a save still running must not store over the reloaded db
+            let _saving = relay.pause_saves();
+            println!("Loading new db...");
+            match DataHolder::from_filerunner() {
+                // the host could offer an older save to undo erasures
+                Ok(Some(db)) if db.generation() < relay.db().generation() => {
+                    println!(
+                        "Admin: refusing to reload save {}, running save {}",
+                        db.generation(),
+                        relay.db().generation()
+                    );
+                    Response::from_string("saved db is older than the running one")
+                        .with_status_code(409)
+                }
+                Ok(Some(mut db)) => {
+                    db.adopt_keys(&relay.db());
+                    db.audit(now, record);
+                    *relay.db_mut() = db;
+                    Response::from_string("OK").with_status_code(200)
+                }
+                Ok(None) => Response::from_string("no saved db").with_status_code(404),
+                Err(e) => {
+                    // keep serving the current db
+                    println!("Admin: failed to load db: {}", e);
+                    Response::from_string("failed to load db").with_status_code(500)
+                }
+            }
+        }
+        AdminAction::Stats => {
+            let stats = serde_json::to_vec(&relay.stats()).unwrap();
+            Response::from_data(stats).with_status_code(200)
+        }
+        AdminAction::Audit => {
+            let audit = serde_json::to_vec(&relay.db().audit.summary()).unwrap();
+            Response::from_data(audit).with_status_code(200)
+        }
+        AdminAction::ReloadConfig => match RelayConfig::from_filerunner() {
+            Ok(mut new_config) => {
+                // the relay is already listening, so network changes need a restart
+                new_config.network = relay.config().network.clone();
+                relay.set_config(new_config);
+                Response::from_string("OK").with_status_code(200)
+            }
+            Err(e) => {
+                println!("Admin: failed to reload config: {}", e);
+                Response::from_string("failed to reload config").with_status_code(500)
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::http_auth::{authorization_header, http_auth_event};
+    use secp256k1::{PublicKey, Secp256k1, SecretKey};
+
+    fn keys(seed: u8) -> (String, String) {
+        let privkey = SecretKey::from_slice(&[seed; 32]).unwrap();
+        let pubkey = PublicKey::from_secret_key(&Secp256k1::new(), &privkey);
+        (
+            hex::encode(privkey.secret_bytes()),
+            hex::encode(pubkey.serialize()),
+        )
+    }
+
+    fn admin_for(pubkey: &str) -> Admin {
+        Admin::new(vec![pubkey.to_string()], &AdminConfig::default())
+    }
+
+    #[test]
+    fn test_authorize_admin() {
+        let (privkey, pubkey) = keys(1);
+        let mut admin = admin_for(&pubkey);
+        let event = http_auth_event(privkey, pubkey.clone(), "/admin/stats", "POST", b"");
+        let header = authorization_header(&event);
+
+        let authorized =
+            admin.authorize(Some(&header), "/admin/stats", "POST", b"", event.created_at);
+        assert_eq!(authorized.unwrap(), pubkey);
+    }
+
+    #[test]
+    fn test_authorize_rejects_non_admin() {
+        let (_, admin_pubkey) = keys(1);
+        let (privkey, pubkey) = keys(2);
+        let mut admin = admin_for(&admin_pubkey);
+        let event = http_auth_event(privkey, pubkey, "/admin/stats", "POST", b"");
+        let header = authorization_header(&event);
+
+        assert!(admin
+            .authorize(Some(&header), "/admin/stats", "POST", b"", event.created_at)
+            .is_err());
+        assert!(admin
+            .authorize(None, "/admin/stats", "POST", b"", event.created_at)
+            .is_err());
+    }
+
+    #[test]
+    fn test_authorize_rejects_replay() {
+        let (privkey, pubkey) = keys(1);
+        let mut admin = admin_for(&pubkey);
+        let event = http_auth_event(privkey, pubkey, "/admin/save", "POST", b"");
+        let header = authorization_header(&event);
+
+        assert!(admin
+            .authorize(Some(&header), "/admin/save", "POST", b"", event.created_at)
+            .is_ok());
+        assert!(admin
+            .authorize(Some(&header), "/admin/save", "POST", b"", event.created_at)
+            .is_err());
+
+        // reloading the config doesn't make it usable again
+        admin.set_config(&AdminConfig::default());
+        assert!(admin
+            .authorize(Some(&header), "/admin/save", "POST", b"", event.created_at)
+            .is_err());
+    }
+
+    #[test]
+    fn test_admin_path_prefix() {
+        let mut admin = Admin::new(vec![], &AdminConfig::default());
+        admin.set_config(&AdminConfig {
+            path_prefix: "/manage/".to_string(),
+        });
+
+        assert_eq!(admin.action("/manage/save"), Some("save"));
+        assert_eq!(admin.action("/admin/save"), None);
+    }
+
+    #[test]
+    fn test_admin_action_from_str() {
+        assert_eq!(AdminAction::from_str("save"), Ok(AdminAction::Save));
+        assert_eq!(
+            AdminAction::from_str("reload-config"),
+            Ok(AdminAction::ReloadConfig)
+        );
+        assert!(AdminAction::from_str("../etc").is_err());
+    }
+}
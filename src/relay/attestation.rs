@@ -0,0 +1,158 @@
+use anyhow::{bail, Result};
+#[cfg(any(test, feature = "mock-attestation"))]
+use core::attestation::MockQuoteGenerator;
+use core::attestation::{self, Attestation, QuoteGenerator, ReportBody};
+use core::channel::Session;
+use core::receipt::ErasureReceipt;
+use secp256k1::{PublicKey, Secp256k1, SecretKey};
+use serde::{Deserialize, Serialize};
+use sgx_isa::{Report, Targetinfo};
+use std::fmt;
+
+/// Length of the nonce a client must send with an info request
+const NONCE_LEN: usize = 32;
+
+/// The relay's long-term keys, sealed under their own label. Keeping the
+/// transport key across restarts keeps erasure receipts verifiable
+/// against the key the relay attests to.
+#[derive(Serialize, Deserialize, Default, Clone, PartialEq)]
+pub struct KeyTable {
+    /// Hex secret transport key, once one was generated
+    transport_key: Option<String>,
+}
+
+impl KeyTable {
+    pub fn transport_key(&self) -> Option<SecretKey> {
+        let key = hex::decode(self.transport_key.as_ref()?).ok()?;
+        SecretKey::from_slice(&key).ok()
+    }
+
+    pub fn set_transport_key(&mut self, key: &SecretKey) {
+        self.transport_key = Some(hex::encode(key.secret_bytes()));
+    }
+}
+
+// keys never end up in logs
+impl fmt::Debug for KeyTable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("KeyTable").finish_non_exhaustive()
+    }
+}
+
+/// A fresh random secret key
+pub fn random_key() -> SecretKey {
+    loop {
+        if let Ok(key) = SecretKey::from_slice(&rand::random::<[u8; 32]>()) {
+            break key;
+        }
+    }
+}
+
+/// Holds the relay's transport key and produces quotes binding it.
+///
+/// The transport key is generated inside the enclave and only leaves it
+/// sealed. Quotes can only be signed by the mock quote generator so far,
+/// which is compiled in for tests and with the `mock-attestation` feature.
+/// A DCAP quoting enclave can be plugged in through `QuoteGenerator`.
+pub struct Attestor {
+    transport_key: SecretKey,
+    generator: Box<dyn QuoteGenerator>,
+}
+
+impl Attestor {
+    #[cfg(test)]
+    pub fn new() -> Self {
+        Self::with_transport_key(random_key()).unwrap()
+    }
+
+    /// Fails if this build has no quote generator to attest with
+    pub fn with_transport_key(transport_key: SecretKey) -> Result<Self> {
+        Ok(Self {
+            transport_key,
+            generator: quote_generator()?,
+        })
+    }
+
+    /// Public half of the transport key
+    pub fn transport_pubkey(&self) -> PublicKey {
+        PublicKey::from_secret_key(&Secp256k1::new(), &self.transport_key)
+    }
+
+    /// Sign `receipt` with the transport key, which the attestation vouches for
+    pub fn sign_receipt(&self, receipt: ErasureReceipt) -> ErasureReceipt {
+        receipt.sign(&self.transport_key)
+    }
+
+    /// Open the encrypted channel for a client's hex session public key
+    pub fn session(&self, client_pubkey: &str) -> Result<Session> {
+        let client_pubkey = PublicKey::from_slice(&hex::decode(client_pubkey)?)?;
+        Ok(Session::relay(&self.transport_key, &client_pubkey))
+    }
+
+    /// Produce an attestation binding the client's hex `nonce` and the transport key
+    pub fn attest(&self, nonce: &str) -> Result<Attestation> {
+        let nonce = hex::decode(nonce)?;
+        if nonce.len() != NONCE_LEN {
+            bail!("nonce must be {} bytes", NONCE_LEN);
+        }
+
+        let transport_pubkey = self.transport_pubkey().serialize();
+        let report_data = attestation::report_data(&nonce, &transport_pubkey);
+
+        let targetinfo = Targetinfo::from(Report::for_self());
+        let report = Report::for_target(&targetinfo, &report_data);
+
+        let quote = self.generator.generate(ReportBody {
+            cpusvn: hex::encode(report.cpusvn),
+            mrenclave: hex::encode(report.mrenclave),
+            mrsigner: hex::encode(report.mrsigner),
+            isvprodid: report.isvprodid,
+            isvsvn: report.isvsvn,
+            report_data: hex::encode(report.reportdata),
+        });
+
+        Ok(Attestation {
+            quote,
+            transport_pubkey: hex::encode(transport_pubkey),
+        })
+    }
+}
+
+/// The mock generator's signing key is public, so anyone can forge its
+/// quotes. It is only ever used by test builds.
+#[cfg(any(test, feature = "mock-attestation"))]
+fn quote_generator() -> Result<Box<dyn QuoteGenerator>> {
+    Ok(Box::new(MockQuoteGenerator))
+}
+
+#[cfg(not(any(test, feature = "mock-attestation")))]
+fn quote_generator() -> Result<Box<dyn QuoteGenerator>> {
+    bail!(
+        "no quote generator available, build with the mock-attestation feature to test without one"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::attestation::MockQuoteVerifier;
+
+    #[test]
+    fn test_attest_verify() {
+        let attestor = Attestor::new();
+        let nonce = [9u8; NONCE_LEN];
+
+        let attestation = attestor.attest(&hex::encode(nonce)).unwrap();
+
+        assert!(attestation.verify(&MockQuoteVerifier, &nonce).is_ok());
+        assert_eq!(
+            attestation.transport_pubkey,
+            hex::encode(attestor.transport_pubkey().serialize())
+        );
+    }
+
+    #[test]
+    fn test_attest_rejects_short_nonce() {
+        assert!(Attestor::new().attest("abcd").is_err());
+    }
+}
@@ -0,0 +1,228 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Something done to stored data. Records say who, how much and which
+/// event ids, never what the data was. Erasures also note the first sealed
+/// state version that no longer holds the erased data.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AuditAction {
+    /// A user asked for their events to be deleted (NIP-09)
+    Deletion {
+        pubkey: String,
+        request_id: String,
+        event_ids: Vec<String>,
+        sealed_version: u64,
+    },
+    /// A user asked for everything about them to be erased (NIP-62)
+    Vanish {
+        pubkey: String,
+        request_id: String,
+        event_ids: Vec<String>,
+        sealed_version: u64,
+    },
+    /// Events dropped without their author asking
+    Purge {
+        reason: PurgeReason,
+        events: usize,
+        users: usize,
+    },
+    /// An admin request, or a management call that changed the moderation rules
+    Admin { pubkey: String, action: String },
+    /// A user was sent a copy of their data
+    Export { pubkey: String, events: usize },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PurgeReason {
+    /// Past their NIP-40 `expiration`
+    Expiration,
+    /// Outside the retention rules
+    Retention,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditRecord {
+    /// Unix time the action happened at
+    pub at: usize,
+    #[serde(flatten)]
+    pub action: AuditAction,
+    /// Hash of the previous record, empty for the first one
+    pub prev: String,
+    /// Hash of this record, over `prev` and its contents
+    pub hash: String,
+}
+
+impl AuditRecord {
+    fn compute_hash(prev: &str, at: usize, action: &AuditAction) -> String {
+        let serialized = serde_json::to_vec(&(prev, at, action)).unwrap();
+        hex::encode(Sha256::digest(serialized))
+    }
+}
+
+/// Append-only record of what was done to stored data and when, sealed
+/// under its own label. Each record commits to the one before it, so
+/// records can't be dropped or reordered without breaking the chain.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct AuditLog {
+    /// Number of times the relay state was sealed and saved, stored here
+    /// because the audit log is saved along with any erasure
+    #[serde(default)]
+    pub sealed_version: u64,
+    pub records: Vec<AuditRecord>,
+}
+
+/// What the admin API reports about the audit log. The host passes admin
+/// responses on in the clear, so pubkeys and event ids stay in the enclave.
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct AuditSummary {
+    pub records: usize,
+    pub deletions: usize,
+    pub vanishes: usize,
+    pub purges: usize,
+    pub exports: usize,
+    pub admin_actions: usize,
+    /// Hash of the newest record, which erasure receipts point to
+    pub head: String,
+    pub sealed_version: u64,
+}
+
+impl AuditLog {
+    /// Hash of the newest record, empty if there is none
+    pub fn head(&self) -> &str {
+        self.records
+            .last()
+            .map_or("", |record| record.hash.as_str())
+    }
+
+    pub fn record(&mut self, at: usize, action: AuditAction) {
+        let prev = self.head().to_string();
+        let hash = AuditRecord::compute_hash(&prev, at, &action);
+        self.records.push(AuditRecord {
+            at,
+            action,
+            prev,
+            hash,
+        });
+    }
+
+    /// Check that every record links to the one before it and matches its hash
+    pub fn verify(&self) -> Result<()> {
+        let mut prev = "";
+        for (index, record) in self.records.iter().enumerate() {
+            if record.prev != prev {
+                bail!("audit record {} does not follow the one before it", index);
+            }
+            if record.hash != AuditRecord::compute_hash(prev, record.at, &record.action) {
+                bail!("audit record {} does not match its hash", index);
+            }
+            prev = &record.hash;
+        }
+        Ok(())
+    }
+
+    /// Whether `pubkey`'s event `event_id` was erased, so it must not be
+    /// stored again when someone who fetched it sends it back
+    pub fn erased(&self, pubkey: &str, event_id: &str) -> bool {
+        self.records.iter().any(|record| match &record.action {
+            AuditAction::Deletion {
+                pubkey: erased_pubkey,
+                event_ids,
+                ..
+            }
+            | AuditAction::Vanish {
+                pubkey: erased_pubkey,
+                event_ids,
+                ..
+            } => erased_pubkey == pubkey && event_ids.iter().any(|id| id == event_id),
+            _ => false,
+        })
+    }
+
+    /// Counts of the records by kind, without who or what they were about
+    pub fn summary(&self) -> AuditSummary {
+        let mut summary = AuditSummary {
+            records: self.records.len(),
+            head: self.head().to_string(),
+            sealed_version: self.sealed_version,
+            ..Default::default()
+        };
+        for record in &self.records {
+            match record.action {
+                AuditAction::Deletion { .. } => summary.deletions += 1,
+                AuditAction::Vanish { .. } => summary.vanishes += 1,
+                AuditAction::Purge { .. } => summary.purges += 1,
+                AuditAction::Export { .. } => summary.exports += 1,
+                AuditAction::Admin { .. } => summary.admin_actions += 1,
+            }
+        }
+        summary
+    }
+
+    /// The erasure asked for by the event `request_id`, if it was executed
+    pub fn erasure(&self, request_id: &str) -> Option<&AuditRecord> {
+        self.records.iter().find(|record| match &record.action {
+            AuditAction::Deletion { request_id: id, .. }
+            | AuditAction::Vanish { request_id: id, .. } => id == request_id,
+            _ => false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn admin(action: &str) -> AuditAction {
+        AuditAction::Admin {
+            pubkey: "02".repeat(33),
+            action: action.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_chain_detects_tampering() {
+        let mut log = AuditLog::default();
+        log.record(1, admin("save"));
+        log.record(
+            2,
+            AuditAction::Deletion {
+                pubkey: "03".repeat(33),
+                request_id: "ab".repeat(32),
+                event_ids: vec!["ef".repeat(32)],
+                sealed_version: 1,
+            },
+        );
+        log.record(3, admin("stats"));
+        assert!(log.verify().is_ok());
+        assert_eq!(log.records[1].prev, log.records[0].hash);
+        assert_eq!(log.erasure(&"ab".repeat(32)).unwrap().at, 2);
+        assert!(log.erasure("cd").is_none());
+
+        let summary = log.summary();
+        assert_eq!(summary.records, 3);
+        assert_eq!(summary.deletions, 1);
+        assert_eq!(summary.admin_actions, 2);
+        assert_eq!(summary.head, log.head());
+        let reported = serde_json::to_string(&summary).unwrap();
+        assert!(!reported.contains(&"03".repeat(33)));
+        assert!(!reported.contains(&"ef".repeat(32)));
+
+        let mut edited = log.clone();
+        edited.records[1].at = 5;
+        assert!(edited.verify().is_err());
+
+        let mut dropped = log.clone();
+        dropped.records.remove(1);
+        assert!(dropped.verify().is_err());
+
+        // rehashing an edited record still breaks the link to the next one
+        let mut rehashed = log;
+        rehashed.records[1].action = admin("reload");
+        rehashed.records[1].hash =
+            AuditRecord::compute_hash(&rehashed.records[0].hash, 2, &rehashed.records[1].action);
+        assert!(rehashed.verify().is_err());
+    }
+}
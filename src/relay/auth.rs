@@ -0,0 +1,163 @@
+use anyhow::{bail, Result};
+use core::event::Event;
+use core::message::AUTH_KIND;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// How long a challenge can be answered for
+const CHALLENGE_TTL: Duration = Duration::from_secs(10 * 60);
+/// How long a session stays authenticated
+const SESSION_TTL: Duration = Duration::from_secs(60 * 60);
+/// Seconds an auth event's `created_at` may be away from now
+const AUTH_WINDOW_SECS: usize = 10 * 60;
+
+/// NIP-42 authentication. Requests reach the relay over plain HTTP, so the
+/// encrypted session stands in for the connection: a session is named by
+/// the client's session public key, and only its holder can send requests
+/// in it. Authenticating binds the session to the pubkey of the signer.
+pub struct Authenticator {
+    /// URL auth events must name in their `relay` tag
+    relay_url: String,
+    /// Open challenge of each session, and when it was issued
+    challenges: HashMap<String, (String, Instant)>,
+    /// Pubkey each session authenticated as, and when
+    sessions: HashMap<String, (String, Instant)>,
+}
+
+impl Authenticator {
+    pub fn new(relay_url: &str) -> Self {
+        Self {
+            relay_url: relay_url.to_string(),
+            challenges: HashMap::new(),
+            sessions: HashMap::new(),
+        }
+    }
+
+    /// A fresh challenge for `session`, replacing any earlier one
+    pub fn challenge(&mut self, session: &str, now: Instant) -> String {
+        let challenge = hex::encode(rand::random::<[u8; 32]>());
+        self.challenges
+            .insert(session.to_string(), (challenge.clone(), now));
+        challenge
+    }
+
+    /// Check `event` answers the challenge of `session`, and bind the
+    /// session to its pubkey. Signatures are checked by the caller.
+    pub fn authenticate(
+        &mut self,
+        session: &str,
+        event: &Event,
+        unix_now: usize,
+        now: Instant,
+    ) -> Result<()> {
+        if event.kind != AUTH_KIND {
+            bail!("auth event must be of kind {}", AUTH_KIND);
+        }
+        if event.created_at.abs_diff(unix_now) > AUTH_WINDOW_SECS {
+            bail!("auth event is too old or too far ahead");
+        }
+        // an auth event signed for another relay must not work here
+        if !event
+            .tag("relay")
+            .is_some_and(|url| same_url(url, &self.relay_url))
+        {
+            bail!("auth event is not for {}", self.relay_url);
+        }
+        let Some((challenge, issued)) = self.challenges.get(session) else {
+            bail!("no challenge was issued for this session");
+        };
+        if now.duration_since(*issued) > CHALLENGE_TTL {
+            bail!("challenge has expired");
+        }
+        if event.tag("challenge") != Some(challenge.as_str()) {
+            bail!("auth event does not answer the challenge");
+        }
+
+        // a challenge only authenticates once
+        self.challenges.remove(session);
+        self.sessions
+            .insert(session.to_string(), (event.pubkey.clone(), now));
+        Ok(())
+    }
+
+    /// Pubkey `session` authenticated as, if it did and hasn't expired
+    pub fn pubkey(&self, session: &str, now: Instant) -> Option<&str> {
+        self.sessions
+            .get(session)
+            .filter(|(_, since)| now.duration_since(*since) <= SESSION_TTL)
+            .map(|(pubkey, _)| pubkey.as_str())
+    }
+
+    /// Forget challenges and sessions that expired
+    pub fn prune(&mut self, now: Instant) {
+        self.challenges
+            .retain(|_, (_, issued)| now.duration_since(*issued) <= CHALLENGE_TTL);
+        self.sessions
+            .retain(|_, (_, since)| now.duration_since(*since) <= SESSION_TTL);
+    }
+}
+
+/// Whether two relay URLs are the same, up to case and a trailing slash
+fn same_url(a: &str, b: &str) -> bool {
+    a.trim_end_matches('/')
+        .eq_ignore_ascii_case(b.trim_end_matches('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RELAY_URL: &str = "http://localhost:8080/";
+
+    fn auth_event(challenge: &str, created_at: usize) -> Event {
+        Event {
+            id: "id".to_string(),
+            pubkey: "02".repeat(33),
+            created_at,
+            kind: AUTH_KIND,
+            tags: vec![
+                vec!["relay".to_string(), "http://localhost:8080".to_string()],
+                vec!["challenge".to_string(), challenge.to_string()],
+            ],
+            content: String::new(),
+            sig: "sig".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_authenticate_session() {
+        let mut auth = Authenticator::new(RELAY_URL);
+        let now = Instant::now();
+        assert!(auth
+            .authenticate("session", &auth_event("guess", 1000), 1000, now)
+            .is_err());
+
+        let challenge = auth.challenge("session", now);
+        // answers for another session, or too late, don't count
+        assert!(auth
+            .authenticate("other", &auth_event(&challenge, 1000), 1000, now)
+            .is_err());
+        assert!(auth
+            .authenticate("session", &auth_event(&challenge, 0), 1000, now)
+            .is_err());
+        assert!(auth.pubkey("session", now).is_none());
+        let mut elsewhere = auth_event(&challenge, 1000);
+        elsewhere.tags[0][1] = "wss://other.relay".to_string();
+        assert!(auth.authenticate("session", &elsewhere, 1000, now).is_err());
+
+        auth.authenticate("session", &auth_event(&challenge, 1000), 1000, now)
+            .unwrap();
+        assert_eq!(auth.pubkey("session", now), Some("02".repeat(33).as_str()));
+        assert!(auth.pubkey("other", now).is_none());
+
+        // the challenge can't be used again
+        assert!(auth
+            .authenticate("session", &auth_event(&challenge, 1000), 1000, now)
+            .is_err());
+
+        let later = now + SESSION_TTL + Duration::from_secs(1);
+        assert!(auth.pubkey("session", later).is_none());
+        auth.prune(later);
+        assert!(auth.sessions.is_empty());
+    }
+}
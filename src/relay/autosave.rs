@@ -0,0 +1,110 @@
+use std::time::{Duration, Instant};
+
+use crate::config::PersistenceConfig;
+use crate::db::DataHolder;
+use crate::server::Relay;
+
+/// Decides when unsaved changes are sealed and sent to the filerunner,
+/// so a crash loses at most one interval's worth of posts.
+pub struct Autosave {
+    interval: Option<Duration>,
+    after_mutations: Option<usize>,
+    last_save: Instant,
+}
+
+impl Autosave {
+    pub fn new(config: &PersistenceConfig) -> Self {
+        Self {
+            interval: (config.autosave_interval_secs > 0)
+                .then(|| Duration::from_secs(config.autosave_interval_secs)),
+            after_mutations: (config.autosave_after_mutations > 0)
+                .then_some(config.autosave_after_mutations),
+            last_save: Instant::now(),
+        }
+    }
+
+    /// Whether `db` has changes that should be saved at `now`
+    pub fn is_due(&self, db: &DataHolder, now: Instant) -> bool {
+        // a db standing in for state that failed to load must not replace it
+        if !db.is_dirty() || db.load_failed() {
+            return false;
+        }
+        let interval_passed = self
+            .interval
+            .is_some_and(|interval| now.duration_since(self.last_save) >= interval);
+        let enough_mutations = self
+            .after_mutations
+            .is_some_and(|after| db.mutations() >= after);
+        interval_passed || enough_mutations
+    }
+
+    /// Save the relay's db if it is due. Failures are logged and retried on the next call.
+    pub fn run(&mut self, relay: &Relay) {
+        let now = Instant::now();
+        if !self.is_due(&relay.db(), now) {
+            return;
+        }
+
+        match relay.save() {
+            Ok(()) => {
+                println!("Autosaved db");
+                self.last_save = now;
+            }
+            Err(e) => println!("Failed to autosave db: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Blob;
+
+    fn autosave(interval_secs: u64, after_mutations: usize) -> Autosave {
+        Autosave::new(&PersistenceConfig {
+            autosave_interval_secs: interval_secs,
+            autosave_after_mutations: after_mutations,
+        })
+    }
+
+    #[test]
+    fn test_clean_db_is_never_due() {
+        let autosave = autosave(1, 1);
+        let db = DataHolder::default();
+
+        let later = autosave.last_save + Duration::from_secs(10);
+        assert!(!autosave.is_due(&db, later));
+    }
+
+    #[test]
+    fn test_due_after_interval() {
+        let autosave = autosave(60, 0);
+        let mut db = DataHolder::default();
+        db.mark_dirty(Blob::Events);
+
+        assert!(!autosave.is_due(&db, autosave.last_save));
+        assert!(autosave.is_due(&db, autosave.last_save + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn test_due_after_mutations() {
+        let autosave = autosave(0, 2);
+        let mut db = DataHolder::default();
+
+        db.mark_dirty(Blob::Events);
+        assert!(!autosave.is_due(&db, autosave.last_save + Duration::from_secs(3600)));
+
+        db.mark_dirty(Blob::Follows);
+        assert!(autosave.is_due(&db, autosave.last_save));
+    }
+
+    #[test]
+    fn test_db_that_failed_to_load_is_never_due() {
+        let autosave = autosave(1, 1);
+        let mut db = DataHolder::not_loaded();
+        db.mark_dirty(Blob::Events);
+
+        assert!(!autosave.is_due(&db, autosave.last_save + Duration::from_secs(10)));
+        assert!(db.save_to_filerunner().is_err());
+    }
+}
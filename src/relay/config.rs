@@ -0,0 +1,528 @@
+use anyhow::{anyhow, bail, Result};
+use core::attestation::Attestation;
+use core::info::{Fees, Info, Kinds, Limitation, Retention};
+use serde::{Deserialize, Serialize};
+
+use crate::filerunner::get_file;
+use crate::management::Moderation;
+
+/// Relay settings, read from `relay.toml` through the filerunner since
+/// the enclave has no filesystem of its own.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct RelayConfig {
+    #[serde(default)]
+    pub network: NetworkConfig,
+    #[serde(default)]
+    pub info: InfoConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    /// Advertised in the relay info and applied by the sweeper
+    #[serde(default)]
+    pub retention: Vec<Retention>,
+    #[serde(default)]
+    pub rate_limits: RateLimitsConfig,
+    #[serde(default)]
+    pub admin: AdminConfig,
+    #[serde(default)]
+    pub persistence: PersistenceConfig,
+    #[serde(default)]
+    pub sweeper: SweeperConfig,
+    #[serde(default)]
+    pub policy: PolicyConfig,
+}
+
+/// Where the relay listens and where it finds the filerunner
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct NetworkConfig {
+    pub bind: String,
+    /// Threads answering requests. Each one needs a thread slot in the
+    /// enclave, see `threads` in Cargo.toml.
+    pub workers: usize,
+    /// The config itself is always fetched from the filerunner given on the
+    /// command line (or the default); this one is used for everything after.
+    pub filerunner: String,
+    /// URL clients reach the relay at. NIP-42 auth events must name it.
+    pub url: String,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            bind: "0.0.0.0:8080".to_string(),
+            workers: 4,
+            filerunner: "0.0.0.0:5555".to_string(),
+            url: "http://localhost:8080/".to_string(),
+        }
+    }
+}
+
+/// Metadata shown in the relay info (NIP-11)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct InfoConfig {
+    pub name: String,
+    pub description: String,
+    pub icon: Option<String>,
+    pub banner: Option<String>,
+    pub contact: Option<String>,
+    /// Hex public key of the relay operator
+    pub pubkey: Option<String>,
+    pub relay_countries: Vec<String>,
+    pub language_tags: Vec<String>,
+    pub tags: Vec<String>,
+    pub posting_policy: Option<String>,
+    pub payments_url: Option<String>,
+    pub fees: Option<Fees>,
+}
+
+impl Default for InfoConfig {
+    fn default() -> Self {
+        Self {
+            name: "Nostrust Relay".to_string(),
+            description: "An attestable GDPR-compliant Nostr relay!".to_string(),
+            icon: Some("https://drive.google.com/file/d/1AdM2UZaxVKjpm_6D45ktWc8wVg0ivxCV/view?usp=sharing".to_string()),
+            banner: None,
+            contact: None,
+            pubkey: None,
+            relay_countries: vec![],
+            language_tags: vec![],
+            tags: vec![],
+            posting_policy: None,
+            payments_url: None,
+            fees: None,
+        }
+    }
+}
+
+/// Limits on what clients may send, advertised in the relay info
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct LimitsConfig {
+    /// Bytes in a request body, including encryption overhead.
+    /// Checked before anything in the body is parsed.
+    pub max_message_length: usize,
+    pub max_content_length: usize,
+    pub max_event_tags: usize,
+    /// Filters in a single REQ
+    pub max_filters: usize,
+    /// Authors a user can follow
+    pub max_subscriptions: usize,
+    /// Events returned for a single request, newest first
+    pub max_limit: usize,
+    /// Seconds an event's `created_at` may lie in the past, 0 for any
+    pub created_at_lower_limit: usize,
+    /// Seconds an event's `created_at` may lie in the future, 0 for any
+    pub created_at_upper_limit: usize,
+    /// Proof of work (NIP-13) new events need, 0 for none
+    pub min_pow_difficulty: usize,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_message_length: 128 * 1024,
+            max_content_length: 64 * 1024,
+            max_event_tags: 2000,
+            max_filters: 10,
+            max_subscriptions: 1000,
+            max_limit: 5000,
+            created_at_lower_limit: 0,
+            created_at_upper_limit: 15 * 60,
+            min_pow_difficulty: 0,
+        }
+    }
+}
+
+/// A token bucket: `burst` requests at once, refilled at `per_minute`.
+/// A rate of 0 per minute turns the limit off.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Rate {
+    pub per_minute: u32,
+    pub burst: u32,
+}
+
+impl Rate {
+    pub fn is_unlimited(&self) -> bool {
+        self.per_minute == 0
+    }
+}
+
+/// Rate for events of some kinds, instead of the default one
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct KindRate {
+    pub kinds: Vec<Kinds>,
+    pub per_minute: u32,
+    pub burst: u32,
+}
+
+impl KindRate {
+    pub fn rate(&self) -> Rate {
+        Rate {
+            per_minute: self.per_minute,
+            burst: self.burst,
+        }
+    }
+}
+
+/// How fast clients may send messages
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct RateLimitsConfig {
+    /// Events per pubkey, for kinds without a rule below
+    pub events: Rate,
+    /// The first rule covering an event's kind is used instead of `events`
+    pub kinds: Vec<KindRate>,
+    /// REQs per authenticated user, or per IP before authenticating
+    #[serde(alias = "reqs_per_connection")]
+    pub reqs_per_user: Rate,
+    /// Each request is its own exchange with the relay, so this limits
+    /// requests rather than open sockets
+    pub connections_per_ip: Rate,
+}
+
+impl Default for RateLimitsConfig {
+    fn default() -> Self {
+        Self {
+            events: Rate {
+                per_minute: 120,
+                burst: 30,
+            },
+            kinds: vec![],
+            reqs_per_user: Rate {
+                per_minute: 60,
+                burst: 20,
+            },
+            connections_per_ip: Rate {
+                per_minute: 600,
+                burst: 100,
+            },
+        }
+    }
+}
+
+impl RateLimitsConfig {
+    /// Which rule limits events of `kind` (None for the default) and its rate
+    pub fn for_kind(&self, kind: usize) -> (Option<usize>, Rate) {
+        self.kinds
+            .iter()
+            .position(|rule| rule.kinds.iter().any(|kinds| kinds.contains(kind)))
+            .map_or((None, self.events), |index| {
+                (Some(index), self.kinds[index].rate())
+            })
+    }
+}
+
+/// Where the admin API is served. The config comes from the untrusted
+/// host, so the admin keys are built into the relay instead.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct AdminConfig {
+    /// Admin requests are POSTed to this prefix followed by the action
+    pub path_prefix: String,
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            path_prefix: "/admin/".to_string(),
+        }
+    }
+}
+
+/// When the relay seals its state and sends it to the filerunner,
+/// besides on shutdown. Either trigger can be turned off with 0.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct PersistenceConfig {
+    /// Save at most this many seconds after the first unsaved change
+    pub autosave_interval_secs: u64,
+    /// Save as soon as this many changes are unsaved
+    pub autosave_after_mutations: usize,
+}
+
+impl Default for PersistenceConfig {
+    fn default() -> Self {
+        Self {
+            autosave_interval_secs: 60,
+            autosave_after_mutations: 100,
+        }
+    }
+}
+
+/// How often expired events (NIP-40) and events outside the retention
+/// rules are purged from the store. Expired events are never served,
+/// whatever the interval.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct SweeperConfig {
+    /// 0 turns the sweeper off
+    pub interval_secs: u64,
+}
+
+impl Default for SweeperConfig {
+    fn default() -> Self {
+        Self { interval_secs: 60 }
+    }
+}
+
+/// Who may post what, on top of the moderation rules set through the
+/// management API. Refused events get a `blocked:` reply.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default)]
+pub struct PolicyConfig {
+    pub mode: PolicyMode,
+    /// Pubkeys that may post in whitelist mode (hex)
+    pub allowed_pubkeys: Vec<String>,
+    pub blocked_pubkeys: Vec<String>,
+    /// If not empty, only these kinds are accepted
+    pub allowed_kinds: Vec<Kinds>,
+    pub blocked_kinds: Vec<Kinds>,
+    /// Also ask the write-policy plugin the filerunner runs about every
+    /// event. Read at startup, and only honoured by relays built with the
+    /// `write-policy-plugin` feature.
+    pub plugin: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PolicyMode {
+    /// Anyone not blocked may post
+    #[default]
+    Open,
+    /// Only allowed pubkeys may post, as on a paid relay
+    Whitelist,
+}
+
+/// Settings given on the command line or in the environment.
+/// Flags win over environment variables, which win over `relay.toml`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Overrides {
+    pub bind: Option<String>,
+    pub filerunner: Option<String>,
+}
+
+impl Overrides {
+    /// Read `--bind` and `--filerunner`, or `NOSTRUST_BIND` and `NOSTRUST_FILERUNNER`
+    pub fn from_env() -> Result<Self> {
+        Self::parse(std::env::args().skip(1), |name| std::env::var(name).ok())
+    }
+
+    fn parse(
+        mut args: impl Iterator<Item = String>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self> {
+        let mut overrides = Self {
+            bind: env("NOSTRUST_BIND"),
+            filerunner: env("NOSTRUST_FILERUNNER"),
+        };
+
+        while let Some(arg) = args.next() {
+            let field = match arg.as_str() {
+                "--bind" => &mut overrides.bind,
+                "--filerunner" => &mut overrides.filerunner,
+                _ => bail!("unknown argument `{}`", arg),
+            };
+            let value = args
+                .next()
+                .ok_or_else(|| anyhow!("{} needs a value", arg))?;
+            *field = Some(value);
+        }
+
+        Ok(overrides)
+    }
+}
+
+impl RelayConfig {
+    /// Fetch the config from the filerunner. A missing file gives the defaults.
+    pub fn from_filerunner() -> Result<Self> {
+        match get_file("/get-config") {
+            Ok(raw) => Ok(toml::from_str(&raw)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn apply(&mut self, overrides: &Overrides) {
+        if let Some(bind) = &overrides.bind {
+            self.network.bind = bind.clone();
+        }
+        if let Some(filerunner) = &overrides.filerunner {
+            self.network.filerunner = filerunner.clone();
+        }
+    }
+
+    /// The relay info (NIP-11) this config describes, with the name and
+    /// policy mode an admin may have changed through the management API
+    pub fn info(&self, moderation: &Moderation, attestation: Option<Attestation>) -> Info {
+        let restricted = moderation.policy_mode(&self.policy) == PolicyMode::Whitelist;
+        Info {
+            name: moderation
+                .relay_name
+                .as_deref()
+                .unwrap_or(&self.info.name)
+                .to_string(),
+            description: self.info.description.clone(),
+            banner: self.info.banner.clone(),
+            icon: self.info.icon.clone(),
+            contact: self.info.contact.clone(),
+            pubkey: self.info.pubkey.clone(),
+            supported_nips: vec![1, 9, 11, 13, 40, 42, 62, 70, 86, 98],
+            software: "https://github.com/karipov/nostrust".to_string(),
+            version: "0.1.0".to_string(),
+            limitation: Some(Limitation {
+                max_message_length: Some(self.limits.max_message_length),
+                max_event_tags: Some(self.limits.max_event_tags),
+                max_content_length: Some(self.limits.max_content_length),
+                max_filters: Some(self.limits.max_filters),
+                max_subscriptions: Some(self.limits.max_subscriptions),
+                max_limit: Some(self.limits.max_limit),
+                created_at_lower_limit: (self.limits.created_at_lower_limit > 0)
+                    .then_some(self.limits.created_at_lower_limit),
+                created_at_upper_limit: (self.limits.created_at_upper_limit > 0)
+                    .then_some(self.limits.created_at_upper_limit),
+                min_pow_difficulty: (self.limits.min_pow_difficulty > 0)
+                    .then_some(self.limits.min_pow_difficulty),
+                // posting works without, but every read needs an authenticated session
+                auth_required: Some(true),
+                payment_required: Some(restricted && self.info.payments_url.is_some()),
+                restricted_writes: Some(restricted),
+                ..Default::default()
+            }),
+            retention: self.retention.clone(),
+            relay_countries: self.info.relay_countries.clone(),
+            language_tags: self.info.language_tags.clone(),
+            tags: self.info.tags.clone(),
+            posting_policy: self.info.posting_policy.clone(),
+            payments_url: self.info.payments_url.clone(),
+            fees: self.info.fees.clone(),
+            attestation,
+            write_policy_plugin: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::info::Kinds;
+
+    fn args(args: &[&str]) -> impl Iterator<Item = String> {
+        args.iter()
+            .map(|arg| arg.to_string())
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let config: RelayConfig = toml::from_str(
+            r#"
+            [network]
+            bind = "127.0.0.1:9000"
+
+            [info]
+            name = "Test Relay"
+
+            [limits]
+            max_event_tags = 10
+
+            [[retention]]
+            kinds = [1, [5, 7]]
+            time = 3600
+
+            [rate_limits]
+            events = { per_minute = 10, burst = 5 }
+
+            [[rate_limits.kinds]]
+            kinds = [[5, 7]]
+            per_minute = 2
+            burst = 1
+
+            [admin]
+            path_prefix = "/manage/"
+
+            [policy]
+            mode = "whitelist"
+            allowed_pubkeys = ["02bb"]
+            blocked_kinds = [[20000, 29999]]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.network.bind, "127.0.0.1:9000");
+        assert_eq!(config.network.filerunner, "0.0.0.0:5555");
+        assert_eq!(config.info.name, "Test Relay");
+        assert_eq!(config.limits.max_event_tags, 10);
+        assert_eq!(config.limits.max_content_length, 64 * 1024);
+        assert_eq!(config.retention[0].kinds[1], Kinds::Range([5, 7]));
+        assert_eq!(config.rate_limits.for_kind(1).0, None);
+        assert_eq!(config.rate_limits.for_kind(1).1.burst, 5);
+        assert_eq!(config.rate_limits.for_kind(6).0, Some(0));
+        assert_eq!(config.rate_limits.for_kind(6).1.per_minute, 2);
+        assert_eq!(config.rate_limits.reqs_per_user.burst, 20);
+        assert_eq!(config.admin.path_prefix, "/manage/");
+        assert_eq!(config.policy.mode, PolicyMode::Whitelist);
+        assert_eq!(
+            config.policy.blocked_kinds,
+            vec![Kinds::Range([20000, 29999])]
+        );
+        assert_eq!(
+            toml::from_str::<RelayConfig>("").unwrap(),
+            RelayConfig::default()
+        );
+
+        // configs written before REQs were limited per user still load
+        let config: RelayConfig =
+            toml::from_str("[rate_limits]\nreqs_per_connection = { per_minute = 1, burst = 3 }")
+                .unwrap();
+        assert_eq!(config.rate_limits.reqs_per_user.burst, 3);
+    }
+
+    #[test]
+    fn test_overrides() {
+        let env = |name: &str| (name == "NOSTRUST_BIND").then(|| "10.0.0.1:80".to_string());
+
+        let overrides = Overrides::parse(args(&[]), env).unwrap();
+        assert_eq!(overrides.bind.as_deref(), Some("10.0.0.1:80"));
+
+        // flags win over the environment
+        let overrides = Overrides::parse(
+            args(&["--bind", "10.0.0.2:80", "--filerunner", "fr:1"]),
+            env,
+        )
+        .unwrap();
+        let mut config = RelayConfig::default();
+        config.apply(&overrides);
+        assert_eq!(config.network.bind, "10.0.0.2:80");
+        assert_eq!(config.network.filerunner, "fr:1");
+
+        assert!(Overrides::parse(args(&["--bind"]), env).is_err());
+        assert!(Overrides::parse(args(&["--verbose"]), env).is_err());
+    }
+
+    #[test]
+    fn test_info_uses_config() {
+        let mut config = RelayConfig::default();
+        config.limits.max_event_tags = 5;
+
+        let mut moderation = Moderation::default();
+        let info = config.info(&moderation, None);
+        assert_eq!(info.name, "Nostrust Relay");
+        let limitation = info.limitation.unwrap();
+        assert_eq!(limitation.max_event_tags, Some(5));
+        assert_eq!(limitation.max_filters, Some(10));
+        assert_eq!(limitation.restricted_writes, Some(false));
+        assert_eq!(limitation.min_pow_difficulty, None);
+        assert_eq!(limitation.auth_required, Some(true));
+
+        moderation.relay_name = Some("Renamed".to_string());
+        moderation.policy_mode = Some(PolicyMode::Whitelist);
+        config.info.payments_url = Some("https://pay.example".to_string());
+        let info = config.info(&moderation, None);
+        assert_eq!(info.name, "Renamed");
+        let limitation = info.limitation.unwrap();
+        assert_eq!(limitation.restricted_writes, Some(true));
+        assert_eq!(limitation.payment_required, Some(true));
+    }
+}
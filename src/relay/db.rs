@@ -1,88 +1,456 @@
-use core::{event::Event, message::{ClientMessage, RelayMessage}};
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use core::info::Info;
-use sgx_isa::{Report, Targetinfo};
+use core::{event::Event, filter::Filter, message::{ClientMessage, DataExport, RelayMessage}, receipt::ErasureReceipt};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::cmp::Reverse;
+use secp256k1::SecretKey;
+use std::collections::{HashMap, HashSet};
+use core::info::Retention;
 
-use std::io::{Read, Write};
-use std::net::TcpStream;
+use anyhow::{bail, Result};
 
-use crate::sealing::{self, SealData};
+use crate::attestation::{self, Attestor, KeyTable};
+use crate::audit::{AuditAction, AuditLog, PurgeReason};
+use crate::config::{LimitsConfig, RelayConfig};
+use crate::filerunner::{get_file, set_file};
+use crate::management::Moderation;
+use crate::manifest::Manifest;
+use crate::policy;
+use crate::sealing::{self, PayloadType, SealData};
 
-const FILERUNNER_SERVER: &str = "0.0.0.0:5555";
+#[derive(Default, Serialize, Deserialize, Debug)]
+pub struct DataHolder {
+    pub events: HashMap<String, Vec<Event>>, // maps user -> list of their posts
+    pub subscribers: HashMap<String, Vec<String>>, // maps user -> list of their subscribers
+    pub subscriptions: HashMap<String, Vec<String>>, // maps user -> list of their subscriptions
+    #[serde(default)]
+    pub moderation: Moderation, // bans and rules set through NIP-86
+    #[serde(default)]
+    pub audit: AuditLog, // what was done to stored data, and when
+    #[serde(skip)]
+    keys: KeyTable,        // the relay's own keys, only ever stored sealed
+    #[serde(skip)]
+    manifest: Manifest,    // the blobs of the last save
+    #[serde(skip)]
+    dirty: Dirty,          // which blobs changed since the last save
+    #[serde(skip)]
+    mutations: usize,      // changes since the last save
+    #[serde(skip)]
+    load_failed: bool,     // saved state failed to load, so never save over it
+}
+
+/// The separately sealed parts of the db
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Blob {
+    Events,
+    Follows,
+    Moderation,
+    Audit,
+    Keys,
+}
+
+impl Blob {
+    const ALL: [Blob; 5] = [
+        Blob::Events,
+        Blob::Follows,
+        Blob::Moderation,
+        Blob::Audit,
+        Blob::Keys,
+    ];
+
+    /// Name of the blob in the manifest, and the start of its file names
+    fn name(&self) -> &'static str {
+        match self {
+            Blob::Events => "events",
+            Blob::Follows => "follows",
+            Blob::Moderation => "moderation",
+            Blob::Audit => "audit",
+            Blob::Keys => "keys",
+        }
+    }
+
+    fn payload_type(&self) -> PayloadType {
+        match self {
+            Blob::Events => PayloadType::Events,
+            Blob::Follows => PayloadType::FollowGraph,
+            Blob::Moderation => PayloadType::Config,
+            Blob::Audit => PayloadType::AuditLog,
+            Blob::Keys => PayloadType::KeyTable,
+        }
+    }
+}
+
+/// Which blobs changed since they were last sealed, so unchanged
+/// state is not sealed and sent again
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+struct Dirty {
+    events: bool,
+    follows: bool,
+    moderation: bool,
+    audit: bool,
+    keys: bool,
+}
 
-fn get_file(endpoint: &str) -> std::io::Result<String> {
-    let mut stream = TcpStream::connect(FILERUNNER_SERVER)?;
+impl Dirty {
+    fn contains(&self, blob: Blob) -> bool {
+        match blob {
+            Blob::Events => self.events,
+            Blob::Follows => self.follows,
+            Blob::Moderation => self.moderation,
+            Blob::Audit => self.audit,
+            Blob::Keys => self.keys,
+        }
+    }
 
-    let request = format!(
-        "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
-        endpoint
-    );
-    stream.write_all(request.as_bytes())?;
-    stream.flush()?;
+    fn merge(&mut self, other: Dirty) {
+        self.events |= other.events;
+        self.follows |= other.follows;
+        self.moderation |= other.moderation;
+        self.audit |= other.audit;
+        self.keys |= other.keys;
+    }
+}
 
-    let mut response = String::new();
-    stream.read_to_string(&mut response)?;
+/// Blobs and a manifest sealed under the db lock, still to be stored
+pub struct PendingSave {
+    /// Endpoints and sealed bodies, the manifest last
+    files: Vec<(String, String)>,
+    manifest: Manifest,
+    /// Blobs that were sealed
+    dirty: Dirty,
+}
 
-    // remove the headers
-    let response = response.split("\r\n\r\n").collect::<Vec<&str>>()[1].to_string();
+impl PendingSave {
+    /// Store the sealed blobs, then the manifest listing them
+    pub fn store(&self, store: Store) -> Result<()> {
+        for (endpoint, sealed) in &self.files {
+            store(endpoint, sealed)?;
+        }
+        Ok(())
+    }
 
-    Ok(response)
+    pub fn store_to_filerunner(&self) -> Result<()> {
+        self.store(&set_file)
+    }
 }
 
-fn set_file(endpoint: &str, body: &str) -> std::io::Result<()> {
-    let mut stream = TcpStream::connect(FILERUNNER_SERVER)?;
+/// Answer to an erasure that isn't in a stored sealed state yet. Its
+/// receipt is only signed once a save holding it was stored.
+pub const ERASURE_NOT_SAVED: &str =
+    "pending: erased, the receipt is signed once the erasure is saved";
+
+/// Reads a stored file, like `get_file`
+type Fetch<'a> = &'a dyn Fn(&str) -> std::io::Result<String>;
+/// Stores a file, like `set_file`
+type Store<'a> = &'a dyn Fn(&str, &str) -> std::io::Result<()>;
 
-    let request = format!(
-        "POST {} HTTP/1.1\r\n\
-         Host: localhost\r\n\
-         Content-Type: text/plain\r\n\
-         Content-Length: {}\r\n\
-         \r\n\
-         {}",
-        endpoint,
-        body.len(),
-        body
-    );
+/// Counters reported by the admin API. Never includes content.
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct Stats {
+    pub users: usize,
+    pub events: usize,
+    pub subscriptions: usize,
+}
 
-    stream.write_all(request.as_bytes())?;
-    stream.flush()?;
+/// Subscription maps, sealed together under their own label
+type FollowGraph = (HashMap<String, Vec<String>>, HashMap<String, Vec<String>>);
 
-    Ok(())
+/// The file from a filerunner answer, or `None` if it was never stored
+fn stored(answer: std::io::Result<String>) -> Result<Option<String>> {
+    match answer {
+        Ok(file) => Ok(Some(file)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
 }
 
-#[derive(Default, Serialize, Deserialize, Debug)]
-pub struct DataHolder {
-    pub events: HashMap<String, Vec<Event>>, // maps user -> list of their posts
-    pub subscribers: HashMap<String, Vec<String>>, // maps user -> list of their subscribers
-    pub subscriptions: HashMap<String, Vec<String>>, // maps user -> list of their subscriptions
+fn unseal_json<T: DeserializeOwned>(payload_type: PayloadType, sealed: &str) -> Result<T> {
+    Ok(serde_json::from_str(&sealing::unseal(
+        payload_type,
+        sealed,
+    )?)?)
+}
+
+/// Fetch and unseal `blob` as listed in `manifest`, or `None` if it was never saved
+fn load_listed<T: DeserializeOwned>(
+    fetch: Fetch,
+    manifest: &Manifest,
+    blob: Blob,
+) -> Result<Option<T>> {
+    let Some(listed) = manifest.blobs.get(blob.name()) else {
+        return Ok(None);
+    };
+    let sealed = fetch(&format!("/get-blob/{}", listed.file))?;
+    manifest.check(blob.name(), &sealed)?;
+    unseal_json(blob.payload_type(), &sealed).map(Some)
+}
+
+/// Fetch and unseal `blob` as saved before there was a manifest, or `None` if it wasn't
+fn load_unlisted<T: DeserializeOwned>(fetch: Fetch, blob: Blob) -> Result<Option<T>> {
+    match stored(fetch(&format!("/get-blob/{}", blob.name())))? {
+        Some(sealed) => unseal_json(blob.payload_type(), &sealed).map(Some),
+        None => Ok(None),
+    }
 }
 
 impl DataHolder {
-    /// Retrieve the db from the filerunner, unseal and deserialize it
-    pub fn from_filerunner() -> Self {
-        let raw_db = get_file("/get-db").unwrap();
-        let raw_seal_data = get_file("/get-sealdata").unwrap();
-        let seal_data: SealData = serde_json::from_str(&raw_seal_data).unwrap();
-        let seal_key = sealing::unseal_key(seal_data).unwrap();
+    /// Retrieve the db from the filerunner, unseal and deserialize it.
+    /// Each data type is stored in its own blob, sealed under its own label.
+    /// Returns `None` if nothing was ever saved.
+    pub fn from_filerunner() -> Result<Option<Self>> {
+        Self::load(&get_file)
+    }
+
+    /// Load the db through `fetch`. Every blob must be the one listed in
+    /// the manifest of the last save. A blob that was never saved is
+    /// empty: blobs are only sealed once they change, so a relay that
+    /// only ever got posts has no follows blob.
+    fn load(fetch: Fetch) -> Result<Option<Self>> {
+        let Some(sealed) = stored(fetch("/get-blob/manifest"))? else {
+            return Self::load_unlisted(fetch);
+        };
+        let manifest: Manifest = unseal_json(PayloadType::Manifest, &sealed)?;
+
+        let audit: AuditLog = load_listed(fetch, &manifest, Blob::Audit)?.unwrap_or_default();
+        audit.verify()?;
+        // every save writes the audit log with the version it counts
+        if audit.sealed_version != manifest.generation {
+            bail!(
+                "audit log is from save {}, the manifest from save {}",
+                audit.sealed_version,
+                manifest.generation
+            );
+        }
+        let (subscribers, subscriptions): FollowGraph =
+            load_listed(fetch, &manifest, Blob::Follows)?.unwrap_or_default();
+
+        Ok(Some(Self {
+            events: load_listed(fetch, &manifest, Blob::Events)?.unwrap_or_default(),
+            subscribers,
+            subscriptions,
+            moderation: load_listed(fetch, &manifest, Blob::Moderation)?.unwrap_or_default(),
+            audit,
+            keys: load_listed(fetch, &manifest, Blob::Keys)?.unwrap_or_default(),
+            manifest,
+            ..Default::default()
+        }))
+    }
 
-        let decrypted_db = sealing::decrypt_string(&seal_key, raw_db).unwrap();
+    /// Load blobs saved before there was a manifest. Nothing ties them
+    /// together, so they are all sealed again with one on the next save.
+    fn load_unlisted(fetch: Fetch) -> Result<Option<Self>> {
+        let events = load_unlisted(fetch, Blob::Events)?;
+        let follows: Option<FollowGraph> = load_unlisted(fetch, Blob::Follows)?;
+        let moderation = load_unlisted(fetch, Blob::Moderation)?;
+        let audit: Option<AuditLog> = load_unlisted(fetch, Blob::Audit)?;
+        if events.is_none() && follows.is_none() && moderation.is_none() && audit.is_none() {
+            return Self::load_legacy(fetch);
+        }
+
+        let audit = audit.unwrap_or_default();
+        audit.verify()?;
+        let (subscribers, subscriptions) = follows.unwrap_or_default();
+        let mut db = Self {
+            events: events.unwrap_or_default(),
+            subscribers,
+            subscriptions,
+            moderation: moderation.unwrap_or_default(),
+            audit,
+            ..Default::default()
+        };
+        db.mark_all_dirty();
+        Ok(Some(db))
+    }
+
+    /// Load a db written as a single blob by an older relay
+    fn load_legacy(fetch: Fetch) -> Result<Option<Self>> {
+        let Some(raw_db) = stored(fetch("/get-db"))? else {
+            return Ok(None);
+        };
+
+        let decrypted_db = if sealing::is_envelope(&raw_db) {
+            sealing::unseal(PayloadType::Database, &raw_db)?
+        } else {
+            // blobs from before the envelope format keep their seal data
+            // in a separate file; they are rewritten as envelopes on the next save
+            let raw_seal_data = fetch("/get-sealdata")?;
+            let seal_data: SealData = serde_json::from_str(&raw_seal_data)?;
+            println!("Retrieved legacy seal data: {:#?}", raw_seal_data);
+            sealing::unseal_legacy(raw_db, seal_data)?
+        };
 
         println!("Retrieved db: {:#?}", decrypted_db);
-        println!("Retrieved seal data: {:#?}", raw_seal_data);
-        serde_json::from_str(&decrypted_db).unwrap()
+        let mut db: Self = serde_json::from_str(&decrypted_db)?;
+
+        // none of the per-type blobs exist yet, so write them all on the next save
+        db.mark_all_dirty();
+        Ok(Some(db))
     }
 
-    /// Serialize and seal the db, send it to the filerunner
-    pub fn to_filerunner(&self) {
-        let raw_db = serde_json::to_string(&self).unwrap();
-        let (seal_key, seal_data) = sealing::seal_key();
+    /// An empty db standing in for saved state that failed to load. It is
+    /// never saved, so it can't overwrite the state it stands in for.
+    pub fn not_loaded() -> Self {
+        Self {
+            load_failed: true,
+            ..Default::default()
+        }
+    }
 
-        let db = sealing::encrypt_string(&seal_key, raw_db).unwrap();
-        let seal_data = serde_json::to_string(&seal_data).unwrap();
+    /// Whether saving is refused because the saved state failed to load
+    pub fn load_failed(&self) -> bool {
+        self.load_failed
+    }
 
-        set_file("/set-sealdata", &seal_data).unwrap();
-        set_file("/set-db", &db).unwrap();
+    /// Serialize and seal the parts of the db that changed since the
+    /// last save, send them to the filerunner
+    pub fn save_to_filerunner(&mut self) -> Result<()> {
+        self.save(&set_file)
+    }
+
+    /// Save through `store`: the changed blobs, then a manifest of the
+    /// whole saved state
+    fn save(&mut self, store: Store) -> Result<()> {
+        let Some(pending) = self.seal_changes()? else {
+            return Ok(());
+        };
+        let stored = pending.store(store);
+        self.finish_save(pending, stored.is_ok());
+        stored
+    }
+
+    /// Seal the blobs that changed, and a manifest of the whole state, as
+    /// the next sealed version. Nothing is sent yet, so the db doesn't
+    /// have to stay locked while `PendingSave::store` runs. Returns `None`
+    /// if nothing changed.
+    pub fn seal_changes(&mut self) -> Result<Option<PendingSave>> {
+        if self.load_failed {
+            bail!("the saved db failed to load, reload it before saving over it");
+        }
+        if !self.is_dirty() {
+            return Ok(None);
+        }
+        // every save is a new sealed state version, counted in the
+        // audit log so receipts can refer to it
+        self.audit.sealed_version += 1;
+        self.mark_dirty(Blob::Audit);
+        match self.seal_dirty_blobs() {
+            Ok(pending) => {
+                // later changes are sealed by the next save
+                self.dirty = Dirty::default();
+                self.mutations = 0;
+                Ok(Some(pending))
+            }
+            Err(error) => {
+                self.audit.sealed_version -= 1;
+                Err(error)
+            }
+        }
+    }
+
+    fn seal_dirty_blobs(&self) -> Result<PendingSave> {
+        let mut manifest = Manifest {
+            generation: self.audit.sealed_version,
+            blobs: self.manifest.blobs.clone(),
+        };
+        let mut files = Vec::new();
+        for blob in Blob::ALL {
+            if !self.dirty.contains(blob) {
+                continue;
+            }
+            let sealed = sealing::seal(blob.payload_type(), self.blob_json(blob)?)?;
+            let file = self.manifest.next_file(blob.name());
+            manifest.insert(blob.name(), file.clone(), &sealed);
+            files.push((format!("/set-blob/{}", file), sealed));
+        }
+
+        // the saved state only changes once the manifest lists the new blobs
+        let sealed = sealing::seal(PayloadType::Manifest, serde_json::to_string(&manifest)?)?;
+        files.push(("/set-blob/manifest".to_string(), sealed));
+        Ok(PendingSave {
+            files,
+            manifest,
+            dirty: self.dirty,
+        })
+    }
+
+    /// Take the outcome of storing a save sealed by `seal_changes`. If it
+    /// failed its blobs are sealed again by the next save. Its version
+    /// stays used either way, as receipts may already refer to the next one.
+    pub fn finish_save(&mut self, pending: PendingSave, stored: bool) {
+        if stored {
+            self.manifest = pending.manifest;
+        } else {
+            self.dirty.merge(pending.dirty);
+        }
+    }
+
+    fn blob_json(&self, blob: Blob) -> serde_json::Result<String> {
+        match blob {
+            Blob::Events => serde_json::to_string(&self.events),
+            Blob::Follows => serde_json::to_string(&(&self.subscribers, &self.subscriptions)),
+            Blob::Moderation => serde_json::to_string(&self.moderation),
+            Blob::Audit => serde_json::to_string(&self.audit),
+            Blob::Keys => serde_json::to_string(&self.keys),
+        }
+    }
+
+    /// Save generation the db was loaded from or last saved as
+    pub fn generation(&self) -> u64 {
+        self.manifest.generation
+    }
+
+    /// The relay's transport key, generated and kept in the key table on first use
+    pub fn transport_key(&mut self) -> SecretKey {
+        if let Some(key) = self.keys.transport_key() {
+            return key;
+        }
+        let key = attestation::random_key();
+        self.keys.set_transport_key(&key);
+        self.mark_dirty(Blob::Keys);
+        key
+    }
+
+    /// Keep the keys of the `running` db, which the relay already uses
+    pub fn adopt_keys(&mut self, running: &DataHolder) {
+        if self.keys != running.keys {
+            self.keys = running.keys.clone();
+            self.mark_dirty(Blob::Keys);
+        }
+    }
+
+    /// Record that a blob changed and has to be sealed again
+    pub fn mark_dirty(&mut self, blob: Blob) {
+        match blob {
+            Blob::Events => self.dirty.events = true,
+            Blob::Follows => self.dirty.follows = true,
+            Blob::Moderation => self.dirty.moderation = true,
+            Blob::Audit => self.dirty.audit = true,
+            Blob::Keys => self.dirty.keys = true,
+        }
+        self.mutations += 1;
+    }
+
+    fn mark_all_dirty(&mut self) {
+        for blob in Blob::ALL {
+            self.mark_dirty(blob);
+        }
+    }
+
+    /// Whether anything changed since the last save
+    pub fn is_dirty(&self) -> bool {
+        self.dirty != Dirty::default()
+    }
+
+    /// Number of changes since the last save
+    pub fn mutations(&self) -> usize {
+        self.mutations
+    }
+
+    /// Counters about the stored data
+    pub fn stats(&self) -> Stats {
+        Stats {
+            users: self.events.len(),
+            events: self.events.values().map(Vec::len).sum(),
+            subscriptions: self.subscriptions.values().map(Vec::len).sum(),
+        }
     }
 
     /// Add an event to the db
@@ -90,6 +458,15 @@ impl DataHolder {
         let user = event.pubkey.clone();
 
         self.events.entry(user.clone()).or_default().push(event);
+        self.mark_dirty(Blob::Events);
+    }
+
+    /// Remove a single event, wherever it is stored
+    pub fn remove_event(&mut self, id: &str) {
+        for events in self.events.values_mut() {
+            events.retain(|event| event.id != id);
+        }
+        self.mark_dirty(Blob::Events);
     }
 
     /// Add a subscription to the db
@@ -102,6 +479,7 @@ impl DataHolder {
             .entry(author.clone())
             .or_default()
             .push(subscriber.clone());
+        self.mark_dirty(Blob::Follows);
     }
 
     /// Remove a subscription from the db
@@ -112,76 +490,999 @@ impl DataHolder {
         if let Some(subscribers) = self.subscribers.get_mut(&subscriber) {
             subscribers.retain(|s| s != &user);
         }
+        self.mark_dirty(Blob::Follows);
+    }
+
+    /// Record an action on stored data in the audit log
+    pub fn audit(&mut self, at: usize, action: AuditAction) {
+        self.audit.record(at, action);
+        self.mark_dirty(Blob::Audit);
+    }
+
+    /// Everything stored for `user`, as sent to them on request
+    fn export(&self, user: &str) -> DataExport {
+        let mut events = self.events.get(user).cloned().unwrap_or_default();
+        events.sort_by_key(|event| event.created_at);
+        DataExport {
+            pubkey: user.to_string(),
+            events,
+            follows: self.subscriptions.get(user).cloned().unwrap_or_default(),
+        }
+    }
+
+    /// Drop the events that the retention rules no longer allow to be kept.
+    /// Returns how many were dropped.
+    pub fn apply_retention(&mut self, retention: &[Retention], now: usize) -> usize {
+        let mut purged = 0;
+        let mut users = 0;
+        for events in self.events.values_mut() {
+            let dropped = retain_events(events, retention, now);
+            purged += dropped;
+            users += usize::from(dropped > 0);
+        }
+        self.events.retain(|_, events| !events.is_empty());
+
+        if purged > 0 {
+            self.mark_dirty(Blob::Events);
+            let action = AuditAction::Purge {
+                reason: PurgeReason::Retention,
+                events: purged,
+                users,
+            };
+            self.audit(now, action);
+        }
+        purged
+    }
+
+    /// Drop every event that expired (NIP-40) by `now`. Returns how many were dropped.
+    pub fn purge_expired(&mut self, now: usize) -> usize {
+        let mut purged = 0;
+        let mut users = 0;
+        for events in self.events.values_mut() {
+            let before = events.len();
+            events.retain(|event| !event.is_expired(now));
+            purged += before - events.len();
+            users += usize::from(events.len() != before);
+        }
+        self.events.retain(|_, events| !events.is_empty());
+
+        if purged > 0 {
+            self.mark_dirty(Blob::Events);
+            let action = AuditAction::Purge {
+                reason: PurgeReason::Expiration,
+                events: purged,
+                users,
+            };
+            self.audit(now, action);
+        }
+        purged
+    }
+
+    /// Whether an event with `id` by `user` is stored. `check_event` made
+    /// sure the id is computed from the event, so a resent event can't
+    /// pass for a new one. The id commits to the pubkey, so only the
+    /// author's events need to be searched.
+    fn has_event(&self, user: &str, id: &str) -> bool {
+        self.events
+            .get(user)
+            .is_some_and(|events| events.iter().any(|event| event.id == id))
     }
 
-    /// GDPR deletion of all events for a user
-    fn delete_events(&mut self, user: String) {
-        self.events.remove(&user);
+    /// GDPR deletion of all events a user posted up to `until`. Later
+    /// ones stay, so replaying an old deletion can't erase new posts.
+    /// Returns the ids of the deleted events.
+    fn delete_events(&mut self, user: &str, until: usize) -> Vec<String> {
+        let mut deleted = Vec::new();
+        if let Some(events) = self.events.get_mut(user) {
+            events.retain(|event| {
+                if event.created_at > until {
+                    return true;
+                }
+                deleted.push(event.id.clone());
+                false
+            });
+            if events.is_empty() {
+                self.events.remove(user);
+            }
+        }
+        self.mark_dirty(Blob::Events);
+        deleted
     }
 
-    pub fn handle_message(&mut self, message: ClientMessage) -> Option<RelayMessage> {
+    /// Erase a user's events up to `until`, and who they follow and who
+    /// follows them (NIP-62). Returns the ids of the deleted events.
+    fn vanish(&mut self, user: &str, until: usize) -> Vec<String> {
+        let deleted = self.delete_events(user, until);
+
+        for author in self.subscriptions.remove(user).unwrap_or_default() {
+            if let Some(subscribers) = self.subscribers.get_mut(&author) {
+                subscribers.retain(|subscriber| subscriber != user);
+            }
+        }
+        for subscriber in self.subscribers.remove(user).unwrap_or_default() {
+            if let Some(subscriptions) = self.subscriptions.get_mut(&subscriber) {
+                subscriptions.retain(|author| author != user);
+            }
+        }
+        self.mark_dirty(Blob::Follows);
+        deleted
+    }
+
+    /// Signed receipt for the erasure asked for by the event `request_id`,
+    /// once the sealed state version it names was stored
+    fn receipt(&self, request_id: &str, attestor: &Attestor) -> Option<ErasureReceipt> {
+        let record = self.audit.erasure(request_id)?;
+        let (pubkey, event_ids, sealed_version, request_kind) = match &record.action {
+            AuditAction::Deletion {
+                pubkey,
+                event_ids,
+                sealed_version,
+                ..
+            } => (pubkey, event_ids, *sealed_version, 5),
+            AuditAction::Vanish {
+                pubkey,
+                event_ids,
+                sealed_version,
+                ..
+            } => (pubkey, event_ids, *sealed_version, 62),
+            _ => return None,
+        };
+        if sealed_version > self.generation() {
+            return None;
+        }
+
+        let receipt = ErasureReceipt {
+            pubkey: pubkey.clone(),
+            request_id: request_id.to_string(),
+            request_kind,
+            executed_at: record.at,
+            event_ids: event_ids.clone(),
+            sealed_version,
+            record_hash: record.hash.clone(),
+            log_head: self.audit.head().to_string(),
+            relay_pubkey: String::new(),
+            sig: String::new(),
+        };
+        Some(attestor.sign_receipt(receipt))
+    }
+
+    /// Handle a message that changes the db. Events must have passed
+    /// `check_event` first, which is kept out of here so the expensive
+    /// signature check doesn't run while the db is locked.
+    pub fn handle_message(
+        &mut self,
+        message: ClientMessage,
+        attestor: &Attestor,
+        config: &RelayConfig,
+    ) -> Option<RelayMessage> {
         match message {
             // event can be a post, deletion
             ClientMessage::Event(event) => {
-                if !event.verify() {
-                    println!("Event failed verification");
-                    return None;
-                }
-
+                let id = event.id.clone();
                 match event.kind {
                     // NIP-11
                     0 => print!("metadata"),
-                    // NIP-09
-                    5 => {
-                        self.delete_events(event.pubkey.clone());
+                    // NIP-09 and NIP-62: both erase everything the user posted so far
+                    5 | 62 => {
+                        if self.audit.erasure(&id).is_some() {
+                            let message = "duplicate: erasure already executed".to_string();
+                            return Some(RelayMessage::Ok(id, true, message));
+                        }
+                        let (pubkey, request_id) = (event.pubkey.clone(), id.clone());
+                        // the erasure is in every state sealed from the next save on
+                        let sealed_version = self.audit.sealed_version + 1;
+                        let action = if event.kind == 5 {
+                            let event_ids = self.delete_events(&pubkey, event.created_at);
+                            AuditAction::Deletion {
+                                pubkey,
+                                request_id,
+                                event_ids,
+                                sealed_version,
+                            }
+                        } else {
+                            let event_ids = self.vanish(&pubkey, event.created_at);
+                            AuditAction::Vanish {
+                                pubkey,
+                                request_id,
+                                event_ids,
+                                sealed_version,
+                            }
+                        };
+                        self.audit(chrono::Utc::now().timestamp() as usize, action);
+                        // the receipt can be asked for once a save stored the erasure
+                        return Some(RelayMessage::Ok(id, true, ERASURE_NOT_SAVED.to_string()));
                     }
                     // NIP-01
-                    _ => self.add_event(event),
+                    _ => {
+                        // deletions above are always honoured, even for banned users
+                        if let Some(reason) = policy::rejects(config, &self.moderation, &event) {
+                            println!("Event blocked: {}", reason);
+                            return Some(RelayMessage::rejected(&id, "blocked", &reason));
+                        }
+                        if self.has_event(&event.pubkey, &id) {
+                            let message = "duplicate: already have this event".to_string();
+                            return Some(RelayMessage::Ok(id, true, message));
+                        }
+                        // anyone who fetched an erased event could undo the erasure
+                        if self.audit.erased(&event.pubkey, &id) {
+                            let reason = "event was erased".to_string();
+                            return Some(RelayMessage::rejected(&id, "blocked", &reason));
+                        }
+                        self.add_event(event);
+                    }
                 }
-                None
+                Some(RelayMessage::Ok(id, true, "".to_string()))
             }
             ClientMessage::Req(user, filters) => {
-                let filter = filters.first().unwrap().clone();
-                let author = filter.authors.unwrap().first().unwrap().clone();
+                let Some(author) = followed_author(&filters) else {
+                    return Some(RelayMessage::Notice(
+                        "invalid: REQ needs a filter with an author".to_string(),
+                    ));
+                };
+                let subscriptions = self.subscriptions.get(&user).map_or(&[][..], Vec::as_slice);
+                if subscriptions.contains(&author) {
+                    return None;
+                }
+                if subscriptions.len() >= config.limits.max_subscriptions {
+                    let reason = format!(
+                        "invalid: cannot follow more than {} authors",
+                        config.limits.max_subscriptions
+                    );
+                    return Some(RelayMessage::Closed(user, reason));
+                }
                 let subscriber = user.clone();
 
                 self.add_subscription(subscriber, author);
                 None
             }
             ClientMessage::Close(user, filters) => {
-                let filter = filters.first().unwrap().clone();
-                let author = filter.authors.unwrap().first().unwrap().clone();
+                let Some(author) = followed_author(&filters) else {
+                    return Some(RelayMessage::Notice(
+                        "invalid: CLOSE needs a filter with an author".to_string(),
+                    ));
+                };
                 let unsubscriber = user.clone();
 
                 self.delete_subscription(unsubscriber, author);
                 None
             }
+            ClientMessage::Export(user) => {
+                let export = self.export(&user);
+                // handing out a user's data is accounted for like erasing it
+                let action = AuditAction::Export {
+                    pubkey: user,
+                    events: export.events.len(),
+                };
+                self.audit(chrono::Utc::now().timestamp() as usize, action);
+                Some(RelayMessage::Export(Box::new(export)))
+            }
+            ClientMessage::Get(_) | ClientMessage::Info(_) | ClientMessage::Receipt(_) => {
+                self.handle_query(message, attestor, config)
+            }
+            // decrypted before it reaches the db
+            ClientMessage::Encrypted(_) => Some(RelayMessage::Notice(
+                "invalid: nested encrypted message".to_string(),
+            )),
+            // sessions are authenticated before they reach the db
+            ClientMessage::Challenge | ClientMessage::Auth(_) => Some(RelayMessage::Notice(
+                "invalid: AUTH needs an encrypted session".to_string(),
+            )),
+        }
+    }
+
+    /// Handle a message that only reads the db, so many can run at once
+    pub fn handle_query(
+        &self,
+        message: ClientMessage,
+        attestor: &Attestor,
+        config: &RelayConfig,
+    ) -> Option<RelayMessage> {
+        match message {
             ClientMessage::Get(user) => {
-                let subscriptions = self.subscriptions.get(&user).unwrap();
+                // users who follow nobody get no events
+                let subscriptions = self.subscriptions.get(&user).map_or(&[][..], Vec::as_slice);
                 let mut retreived_events: Vec<Event> = vec![];
+                let now = chrono::Utc::now().timestamp() as usize;
                 for subscription in subscriptions {
                     if let Some(events) = self.events.get(subscription) {
-                        retreived_events.extend(events.clone());
+                        // expired events wait for the sweeper, but are never served
+                        let live = events.iter().filter(|event| !event.is_expired(now));
+                        retreived_events.extend(live.cloned());
                     }
                 }
+                newest(&mut retreived_events, config.limits.max_limit);
 
                 // send all events to the user
                 Some(RelayMessage::Events(retreived_events))
             }
-            ClientMessage::Info => {
-                let targetinfo = Targetinfo::from(Report::for_self());
-                let info = Info {
-                    name: "Nostrust Relay".to_string(),
-                    description: "An attestable GDPR-compliant Nostr relay!".to_string(),
-                    icon: Some("https://drive.google.com/file/d/1AdM2UZaxVKjpm_6D45ktWc8wVg0ivxCV/view?usp=sharing".to_string()),
-                    supported_nips: vec![1, 9, 11],
-                    software: "https://github.com/karipov/nostrust".to_string(),
-                    version: "0.1.0".to_string(),
-                    attestation: targetinfo.measurement,
-                    ..Default::default()
+            ClientMessage::Info(nonce) => {
+                let attestation = match attestor.attest(&nonce) {
+                    Ok(attestation) => Some(attestation),
+                    Err(e) => {
+                        println!("Failed to attest: {}", e);
+                        None
+                    }
                 };
-                Some(RelayMessage::Info(info))
+                let info = config.info(&self.moderation, attestation);
+                Some(RelayMessage::Info(Box::new(info)))
+            }
+            ClientMessage::Receipt(request_id) => match self.receipt(&request_id, attestor) {
+                Some(receipt) => Some(RelayMessage::Receipt(Box::new(receipt))),
+                None if self.audit.erasure(&request_id).is_some() => Some(RelayMessage::Notice(
+                    "error: the erasure is not saved yet, ask again later".to_string(),
+                )),
+                None => Some(RelayMessage::Notice(
+                    "invalid: no erasure was executed for that request".to_string(),
+                )),
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Drop the events of one user that `retention` no longer allows to be
+/// kept at `now`. Returns how many were dropped.
+fn retain_events(events: &mut Vec<Event>, retention: &[Retention], now: usize) -> usize {
+    let before = events.len();
+
+    for rule in retention {
+        if let Some(time) = rule.time {
+            events.retain(|event| {
+                !rule.applies_to(event.kind) || event.created_at.saturating_add(time) >= now
+            });
+        }
+        if let Some(count) = rule.count {
+            let mut matching: Vec<usize> = (0..events.len())
+                .filter(|&i| rule.applies_to(events[i].kind))
+                .collect();
+            if matching.len() > count {
+                // keep the newest `count` events of these kinds
+                matching.sort_by_key(|&i| Reverse(events[i].created_at));
+                let dropped: HashSet<usize> = matching[count..].iter().copied().collect();
+                let mut index = 0;
+                events.retain(|_| {
+                    index += 1;
+                    !dropped.contains(&(index - 1))
+                });
+            }
+        }
+    }
+
+    before - events.len()
+}
+
+/// Keep only the newest `limit` of `events`, oldest first
+fn newest(events: &mut Vec<Event>, limit: usize) {
+    if events.len() > limit {
+        events.sort_by_key(|event| event.created_at);
+        events.drain(..events.len() - limit);
+    }
+}
+
+/// The author a follow (REQ) or unfollow (CLOSE) is about
+fn followed_author(filters: &[Filter]) -> Option<String> {
+    filters.first()?.authors.as_ref()?.first().cloned()
+}
+
+/// Whether `message` only reads the db
+pub fn is_query(message: &ClientMessage) -> bool {
+    matches!(
+        message,
+        ClientMessage::Get(_) | ClientMessage::Info(_) | ClientMessage::Receipt(_)
+    )
+}
+
+/// Why `event` may not be stored regardless of the db's state, if it may not
+pub fn check_event(event: &Event, limits: &LimitsConfig, now: usize) -> Option<String> {
+    if let Some(reason) = exceeds_limits(event, limits) {
+        return Some(reason);
+    }
+    if let Some(reason) = outside_time_bounds(event, limits, now) {
+        return Some(reason);
+    }
+    if event.is_expired(now) {
+        return Some("event has already expired".to_string());
+    }
+    if !event.verify() {
+        return Some("event failed verification".to_string());
+    }
+    None
+}
+
+/// Why `event` lacks the proof of work (NIP-13) the relay asks for, if it
+/// does. Erasure requests never need any. The event must have passed
+/// `check_event`, so its id is the one computed from it.
+pub fn check_pow(event: &Event, limits: &LimitsConfig) -> Option<String> {
+    let required = limits.min_pow_difficulty;
+    if required == 0 || matches!(event.kind, 5 | 62) || event.pow() >= required {
+        return None;
+    }
+    Some(match event.committed_target() {
+        Some(target) if target < required => {
+            format!("committed target {} is less than {}", target, required)
+        }
+        _ => format!(
+            "difficulty {} is less than {}",
+            event.difficulty(),
+            required
+        ),
+    })
+}
+
+/// Why a REQ with `filters` is larger than the relay accepts, if it is
+pub fn check_req(filters: &[Filter], limits: &LimitsConfig) -> Option<String> {
+    if filters.len() > limits.max_filters {
+        return Some(format!("more than {} filters", limits.max_filters));
+    }
+    None
+}
+
+/// Why the `created_at` of `event` is too far from `now`, if it is
+fn outside_time_bounds(event: &Event, limits: &LimitsConfig, now: usize) -> Option<String> {
+    let lower = limits.created_at_lower_limit;
+    if lower > 0 && event.created_at < now.saturating_sub(lower) {
+        return Some(format!("created_at is more than {} seconds ago", lower));
+    }
+    let upper = limits.created_at_upper_limit;
+    if upper > 0 && event.created_at > now.saturating_add(upper) {
+        return Some(format!("created_at is more than {} seconds ahead", upper));
+    }
+    None
+}
+
+/// Why `event` is larger than the relay accepts, if it is
+fn exceeds_limits(event: &Event, limits: &LimitsConfig) -> Option<String> {
+    if event.content.len() > limits.max_content_length {
+        return Some(format!(
+            "content is longer than {} bytes",
+            limits.max_content_length
+        ));
+    }
+    if event.tags.len() > limits.max_event_tags {
+        return Some(format!("more than {} tags", limits.max_event_tags));
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::info::Kinds;
+    use secp256k1::{PublicKey, Secp256k1};
+    use std::cell::RefCell;
+
+    fn event(kind: usize, created_at: usize) -> Event {
+        Event {
+            id: format!("{}-{}", kind, created_at),
+            pubkey: "02".repeat(33),
+            created_at,
+            kind,
+            tags: vec![],
+            content: "content".to_string(),
+            sig: "sig".to_string(),
+        }
+    }
+
+    fn ids(db: &DataHolder) -> Vec<String> {
+        db.events[&"02".repeat(33)]
+            .iter()
+            .map(|event| event.id.clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_retention_by_time() {
+        let mut db = DataHolder::default();
+        db.add_event(event(1, 100));
+        db.add_event(event(1, 1000));
+        db.add_event(event(7, 100));
+        let retention = vec![Retention {
+            kinds: vec![Kinds::Kind(1)],
+            time: Some(500),
+            count: None,
+        }];
+
+        assert_eq!(db.apply_retention(&retention, 1000), 1);
+
+        assert_eq!(ids(&db), ["1-1000", "7-100"]);
+        assert_eq!(
+            db.audit.records[0].action,
+            AuditAction::Purge {
+                reason: PurgeReason::Retention,
+                events: 1,
+                users: 1
+            }
+        );
+        assert_eq!(db.audit.records[0].at, 1000);
+    }
+
+    #[test]
+    fn test_retention_by_count() {
+        let mut db = DataHolder::default();
+        db.add_event(event(1, 300));
+        db.add_event(event(1, 100));
+        db.add_event(event(1, 200));
+        let retention = vec![Retention {
+            count: Some(2),
+            ..Default::default()
+        }];
+
+        db.apply_retention(&retention, 1000);
+
+        // the oldest post goes, the order of the rest is kept
+        assert_eq!(ids(&db), ["1-300", "1-200"]);
+    }
+
+    #[test]
+    fn test_check_pow() {
+        let limits = LimitsConfig {
+            min_pow_difficulty: 8,
+            ..Default::default()
+        };
+        let mut event = event(1, 0);
+        event.id = format!("00{}", "ff".repeat(31));
+        assert!(check_pow(&event, &limits).is_none());
+
+        event.id = format!("01{}", "ff".repeat(31));
+        assert!(check_pow(&event, &limits).is_some());
+        assert!(check_pow(&event, &LimitsConfig::default()).is_none());
+
+        // enough work, but committed to less
+        event.id = format!("0000{}", "ff".repeat(30));
+        event.tags = vec![vec!["nonce".into(), "1".into(), "4".into()]];
+        assert!(check_pow(&event, &limits)
+            .unwrap()
+            .starts_with("committed target"));
+
+        event.kind = 5;
+        assert!(check_pow(&event, &limits).is_none());
+    }
+
+    #[test]
+    fn test_forged_id_fails_check_event() {
+        let privkey = SecretKey::from_slice(&[3u8; 32]).unwrap();
+        let pubkey = PublicKey::from_secret_key(&Secp256k1::new(), &privkey);
+        let mut event = Event::new(
+            hex::encode(privkey.secret_bytes()),
+            hex::encode(pubkey.serialize()),
+            1,
+            vec![],
+            "no work done".to_string(),
+        );
+        let limits = LimitsConfig::default();
+        let now = event.created_at;
+        assert!(check_event(&event, &limits, now).is_none());
+
+        event.id = "0".repeat(64);
+        assert!(check_event(&event, &limits, now).is_some());
+    }
+
+    #[test]
+    fn test_exceeds_limits() {
+        let limits = LimitsConfig {
+            max_content_length: 4,
+            max_event_tags: 1,
+            ..Default::default()
+        };
+        let mut event = event(1, 0);
+        event.content = "four".to_string();
+        assert!(exceeds_limits(&event, &limits).is_none());
+
+        event.content = "fives".to_string();
+        assert!(exceeds_limits(&event, &limits).is_some());
+
+        event.content = String::new();
+        event.tags = vec![vec!["t".to_string()], vec!["p".to_string()]];
+        assert!(exceeds_limits(&event, &limits).is_some());
+
+        let limits = LimitsConfig {
+            max_filters: 1,
+            ..Default::default()
+        };
+        assert!(check_req(&[Filter::default()], &limits).is_none());
+        assert!(check_req(&[Filter::default(), Filter::default()], &limits).is_some());
+    }
+
+    #[test]
+    fn test_replayed_events() {
+        let config = RelayConfig::default();
+        let attestor = Attestor::new();
+        let mut db = DataHolder::default();
+        let post = |db: &mut DataHolder, event: Event| {
+            db.handle_message(ClientMessage::Event(event), &attestor, &config)
+        };
+
+        let first = post(&mut db, event(1, 100));
+        assert_eq!(
+            first,
+            Some(RelayMessage::Ok("1-100".into(), true, "".into()))
+        );
+        let replay = post(&mut db, event(1, 100));
+        assert!(matches!(
+            replay,
+            Some(RelayMessage::Ok(_, true, message)) if message.starts_with("duplicate:")
+        ));
+        assert_eq!(ids(&db), ["1-100"]);
+
+        // a replayed deletion only erases what was posted before it
+        let deletion = event(5, 150);
+        post(&mut db, deletion.clone());
+        assert!(db.events.is_empty());
+        post(&mut db, event(1, 200));
+        post(&mut db, deletion);
+        assert_eq!(ids(&db), ["1-200"]);
+
+        // an erased event sent again, say by a follower, is not stored again
+        let resent = post(&mut db, event(1, 100));
+        assert!(matches!(
+            resent,
+            Some(RelayMessage::Ok(_, false, message)) if message.starts_with("blocked:")
+        ));
+        assert_eq!(ids(&db), ["1-200"]);
+    }
+
+    #[test]
+    fn test_erasures_are_audited_with_receipts() {
+        let config = RelayConfig::default();
+        let attestor = Attestor::new();
+        let mut db = DataHolder::default();
+        let user = "02".repeat(33);
+        db.add_event(event(1, 100));
+        db.add_subscription(user.clone(), "author".to_string());
+        db.add_subscription("follower".to_string(), user.clone());
+
+        let vanish = event(62, 150);
+        let reply = db.handle_message(ClientMessage::Event(vanish.clone()), &attestor, &config);
+        assert_eq!(
+            reply,
+            Some(RelayMessage::Ok(
+                "62-150".into(),
+                true,
+                ERASURE_NOT_SAVED.into()
+            ))
+        );
+        let ask = |db: &DataHolder| {
+            db.handle_query(ClientMessage::Receipt("62-150".into()), &attestor, &config)
+        };
+        assert!(
+            matches!(ask(&db), Some(RelayMessage::Notice(message)) if message.starts_with("error:"))
+        );
+
+        // no receipt names a version whose save failed to be stored
+        let pending = db.seal_changes().unwrap().unwrap();
+        db.finish_save(pending, false);
+        assert!(matches!(ask(&db), Some(RelayMessage::Notice(_))));
+
+        let files = Files::default();
+        files.save(&mut db);
+        let reply = ask(&db);
+        let Some(RelayMessage::Receipt(executed)) = reply else {
+            panic!("expected a receipt, got {:?}", reply);
+        };
+        assert!(executed.verify());
+        assert!(executed.sealed_version <= db.generation());
+        assert_eq!(executed.event_ids, vec!["1-100".to_string()]);
+        assert_eq!(executed.sealed_version, 1);
+        assert!(db.events.is_empty());
+        assert!(db.subscriptions[&"follower".to_string()].is_empty());
+        assert!(!db.subscriptions.contains_key(&user));
+        assert!(db.subscribers[&"author".to_string()].is_empty());
+
+        let reply = db.handle_message(ClientMessage::Event(vanish), &attestor, &config);
+        assert!(matches!(
+            reply,
+            Some(RelayMessage::Ok(_, true, message)) if message.starts_with("duplicate:")
+        ));
+        assert_eq!(db.audit.records.len(), 1);
+        assert!(db.audit.verify().is_ok());
+
+        let reply = db.handle_query(ClientMessage::Receipt("62-150".into()), &attestor, &config);
+        let Some(RelayMessage::Receipt(receipt)) = reply else {
+            panic!("expected a receipt, got {:?}", reply);
+        };
+        assert!(receipt.verify());
+        assert_eq!(receipt.pubkey, user);
+        assert_eq!(receipt.event_ids, executed.event_ids);
+        assert_eq!(receipt.sealed_version, 1);
+        assert_eq!(receipt.request_kind, 62);
+        assert_eq!(receipt.record_hash, db.audit.head());
+        assert_eq!(
+            receipt.relay_pubkey,
+            hex::encode(attestor.transport_pubkey().serialize())
+        );
+
+        let reply = db.handle_query(ClientMessage::Receipt("5-1".into()), &attestor, &config);
+        assert!(matches!(reply, Some(RelayMessage::Notice(_))));
+    }
+
+    #[test]
+    fn test_exports_are_audited() {
+        let config = RelayConfig::default();
+        let attestor = Attestor::new();
+        let mut db = DataHolder::default();
+        let user = "02".repeat(33);
+        db.add_event(event(1, 200));
+        db.add_event(event(1, 100));
+        db.add_subscription(user.clone(), "author".to_string());
+
+        let reply = db.handle_message(ClientMessage::Export(user.clone()), &attestor, &config);
+        let Some(RelayMessage::Export(export)) = reply else {
+            panic!("expected an export, got {:?}", reply);
+        };
+        let ids: Vec<&str> = export
+            .events
+            .iter()
+            .map(|event| event.id.as_str())
+            .collect();
+        assert_eq!(ids, ["1-100", "1-200"]);
+        assert_eq!(export.follows, ["author"]);
+
+        let record = db.audit.records.last().unwrap();
+        assert_eq!(
+            record.action,
+            AuditAction::Export {
+                pubkey: user,
+                events: 2
+            }
+        );
+        assert!(db.is_dirty());
+    }
+
+    /// Files of a fake filerunner
+    #[derive(Default)]
+    struct Files(RefCell<HashMap<String, String>>);
+
+    impl Files {
+        /// Like `get_file`
+        fn fetch(&self, endpoint: &str) -> std::io::Result<String> {
+            self.0.borrow().get(endpoint).cloned().ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::NotFound, endpoint.to_string())
+            })
+        }
+
+        /// Like `set_file`
+        fn store(&self, endpoint: &str, body: &str) -> std::io::Result<()> {
+            let endpoint = endpoint.replace("/set-", "/get-");
+            self.0.borrow_mut().insert(endpoint, body.to_string());
+            Ok(())
+        }
+
+        fn get(&self, endpoint: &str) -> String {
+            self.fetch(endpoint).unwrap()
+        }
+
+        fn set(&self, endpoint: &str, body: String) {
+            self.0.borrow_mut().insert(endpoint.to_string(), body);
+        }
+
+        fn load(&self) -> Result<Option<DataHolder>> {
+            DataHolder::load(&|endpoint| self.fetch(endpoint))
+        }
+
+        fn save(&self, db: &mut DataHolder) {
+            db.save(&|endpoint, body| self.store(endpoint, body))
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_save_and_load_blobs() {
+        let files = Files::default();
+        assert!(files.load().unwrap().is_none());
+
+        // a relay that only ever got posts has no follows or moderation blob
+        let mut db = DataHolder::default();
+        db.add_event(event(1, 100));
+        let key = db.transport_key();
+        files.save(&mut db);
+        assert!(!db.manifest.blobs.contains_key("follows"));
+
+        let loaded = files.load().unwrap().unwrap();
+        assert_eq!(loaded.stats().events, 1);
+        assert!(loaded.subscriptions.is_empty());
+        assert_eq!(loaded.generation(), 1);
+        assert_eq!(loaded.keys.transport_key(), Some(key));
+        assert!(!loaded.is_dirty());
+
+        // unchanged blobs stay where they are, changed ones go to the other slot
+        db.add_subscription("me".into(), "02".repeat(33));
+        files.save(&mut db);
+        let loaded = files.load().unwrap().unwrap();
+        assert_eq!(loaded.generation(), 2);
+        assert_eq!(loaded.manifest.blobs["events"].file, "events-0");
+        assert_eq!(loaded.manifest.blobs["audit"].file, "audit-1");
+        assert_eq!(loaded.stats().subscriptions, 1);
+    }
+
+    #[test]
+    fn test_blobs_from_other_saves_are_detected() {
+        let files = Files::default();
+        let mut db = DataHolder::default();
+        db.add_event(event(1, 100));
+        files.save(&mut db);
+        let old_events = files.get("/get-blob/events-0");
+        let first_manifest = files.get("/get-blob/manifest");
+
+        // an erasure, then the host puts the events back
+        db.delete_events(&"02".repeat(33), 100);
+        files.save(&mut db);
+        assert_eq!(files.load().unwrap().unwrap().stats().events, 0);
+        files.set("/get-blob/events-1", old_events.clone());
+        assert!(files.load().is_err());
+
+        // an old manifest doesn't match the blobs saved since
+        db.add_event(event(1, 200));
+        files.save(&mut db);
+        files.set("/get-blob/events-1", old_events);
+        files.set("/get-blob/manifest", first_manifest);
+        assert!(files.load().is_err());
+    }
+
+    #[test]
+    fn test_blobs_saved_before_the_manifest_are_migrated() {
+        let files = Files::default();
+        let events = HashMap::from([("02".repeat(33), vec![event(1, 100)])]);
+        let sealed =
+            sealing::seal(PayloadType::Events, serde_json::to_string(&events).unwrap()).unwrap();
+        files.set("/get-blob/events", sealed);
+        // stale legacy state is ignored once blobs exist
+        files.set("/get-db", "stale".to_string());
+
+        let mut db = files.load().unwrap().unwrap();
+        assert_eq!(db.stats().events, 1);
+        assert!(db.is_dirty());
+        files.save(&mut db);
+        assert_eq!(files.load().unwrap().unwrap().stats().events, 1);
+    }
+
+    #[test]
+    fn test_changes_while_a_save_is_stored_are_kept() {
+        let files = Files::default();
+        let mut db = DataHolder::default();
+        db.add_event(event(1, 100));
+
+        // the db is changed while the sealed save is sent, and storing fails
+        let pending = db.seal_changes().unwrap().unwrap();
+        db.add_subscription("me".into(), "02".repeat(33));
+        let unreachable = |_: &str, _: &str| -> std::io::Result<()> {
+            Err(std::io::ErrorKind::ConnectionRefused.into())
+        };
+        assert!(pending.store(&unreachable).is_err());
+        db.finish_save(pending, false);
+        assert!(db.dirty.contains(Blob::Events));
+        assert!(db.dirty.contains(Blob::Follows));
+
+        // the next save has both, as a new version
+        files.save(&mut db);
+        let loaded = files.load().unwrap().unwrap();
+        assert_eq!(loaded.generation(), 2);
+        assert_eq!(loaded.stats().events, 1);
+        assert_eq!(loaded.stats().subscriptions, 1);
+
+        // a change made while a save is stored waits for the next one
+        db.add_event(event(1, 200));
+        let pending = db.seal_changes().unwrap().unwrap();
+        db.add_event(event(1, 300));
+        pending
+            .store(&|endpoint, body| files.store(endpoint, body))
+            .unwrap();
+        db.finish_save(pending, true);
+        assert_eq!(files.load().unwrap().unwrap().stats().events, 2);
+        assert!(db.dirty.contains(Blob::Events));
+        files.save(&mut db);
+        assert_eq!(files.load().unwrap().unwrap().stats().events, 3);
+    }
+
+    #[test]
+    fn test_load_fails_when_filerunner_fails() {
+        let unreachable = |_: &str| -> std::io::Result<String> {
+            Err(std::io::ErrorKind::ConnectionRefused.into())
+        };
+        assert!(DataHolder::load(&unreachable).is_err());
+    }
+
+    #[test]
+    fn test_failed_manifest_read_is_not_a_missing_save() {
+        let files = Files::default();
+        let mut db = DataHolder::default();
+        db.add_event(event(1, 100));
+        files.save(&mut db);
+
+        // the filerunner answers 500, as for a file it failed to read
+        let failing = |endpoint: &str| -> std::io::Result<String> {
+            if endpoint == "/get-blob/manifest" {
+                return Err(std::io::Error::other("HTTP/1.1 500 Internal Server Error"));
             }
+            files.fetch(endpoint)
+        };
+        assert!(DataHolder::load(&failing).is_err());
+    }
+
+    #[test]
+    fn test_expired_events() {
+        let expiring = |created_at: usize, expiration: usize| {
+            let mut event = event(1, created_at);
+            event.tags = vec![vec!["expiration".into(), expiration.to_string()]];
+            event
+        };
+        let mut db = DataHolder::default();
+        db.add_event(expiring(100, 200));
+        db.add_event(event(1, 150));
+        db.add_event(expiring(300, 4_000_000_000));
+        db.subscriptions.insert("me".into(), vec!["02".repeat(33)]);
+        db.dirty = Dirty::default();
+
+        let limits = LimitsConfig::default();
+        assert!(check_event(&expiring(100, 200), &limits, 200).is_some());
+
+        // hidden from queries right away
+        let config = RelayConfig::default();
+        let reply = db.handle_query(ClientMessage::Get("me".into()), &Attestor::new(), &config);
+        let Some(RelayMessage::Events(events)) = reply else {
+            panic!("expected events, got {:?}", reply);
+        };
+        assert_eq!(events.len(), 2);
+
+        assert_eq!(db.purge_expired(199), 0);
+        assert!(!db.is_dirty());
+        assert!(db.audit.records.is_empty());
+        assert_eq!(db.purge_expired(200), 1);
+        assert!(db.is_dirty());
+        assert_eq!(db.audit.records.len(), 1);
+        assert_eq!(ids(&db), ["1-150", "1-300"]);
+    }
+
+    #[test]
+    fn test_outside_time_bounds() {
+        let limits = LimitsConfig {
+            created_at_lower_limit: 100,
+            created_at_upper_limit: 10,
+            ..Default::default()
+        };
+        let now = 1000;
+
+        assert!(outside_time_bounds(&event(1, 900), &limits, now).is_none());
+        assert!(outside_time_bounds(&event(1, 1010), &limits, now).is_none());
+        assert!(outside_time_bounds(&event(1, 899), &limits, now).is_some());
+        assert!(outside_time_bounds(&event(1, 1011), &limits, now).is_some());
+
+        let unbounded = LimitsConfig {
+            created_at_lower_limit: 0,
+            created_at_upper_limit: 0,
+            ..Default::default()
+        };
+        assert!(outside_time_bounds(&event(1, 0), &unbounded, now).is_none());
+        assert!(outside_time_bounds(&event(1, usize::MAX), &unbounded, now).is_none());
+    }
+
+    #[test]
+    fn test_subscription_and_result_limits() {
+        let mut config = RelayConfig::default();
+        config.limits.max_subscriptions = 1;
+        config.limits.max_limit = 2;
+        let attestor = Attestor::new();
+        let mut db = DataHolder::default();
+        for created_at in [300, 100, 200] {
+            db.add_event(event(1, created_at));
         }
+
+        let mut follow = |author: String| {
+            let filters = vec![Filter::one_author(author)];
+            db.handle_message(ClientMessage::Req("me".into(), filters), &attestor, &config)
+        };
+        assert_eq!(follow("02".repeat(33)), None);
+        // following the same author again is fine
+        assert_eq!(follow("02".repeat(33)), None);
+        assert!(matches!(
+            follow("03".repeat(33)),
+            Some(RelayMessage::Closed(..))
+        ));
+
+        let reply = db.handle_query(ClientMessage::Get("me".to_string()), &attestor, &config);
+        let Some(RelayMessage::Events(events)) = reply else {
+            panic!("expected events, got {:?}", reply);
+        };
+        let ids: Vec<_> = events.iter().map(|event| event.id.as_str()).collect();
+        assert_eq!(ids, ["1-200", "1-300"]);
     }
 }
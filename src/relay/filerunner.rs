@@ -0,0 +1,140 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::RwLock;
+use std::time::Duration;
+
+const DEFAULT_FILERUNNER_SERVER: &str = "0.0.0.0:5555";
+/// How long a read or write to the filerunner may block. The write-policy
+/// plugin has its own, shorter timeout in the filerunner.
+const FILERUNNER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Address of the filerunner, set from the config at startup
+static FILERUNNER_SERVER: RwLock<String> = RwLock::new(String::new());
+
+/// Send all following filerunner requests to `address`
+pub fn set_server(address: &str) {
+    *FILERUNNER_SERVER.write().unwrap() = address.to_string();
+}
+
+fn connect() -> std::io::Result<TcpStream> {
+    let address = FILERUNNER_SERVER.read().unwrap();
+    let stream = if address.is_empty() {
+        TcpStream::connect(DEFAULT_FILERUNNER_SERVER)?
+    } else {
+        TcpStream::connect(address.as_str())?
+    };
+    // a filerunner that stops answering must not hold relay workers forever
+    stream.set_read_timeout(Some(FILERUNNER_TIMEOUT))?;
+    stream.set_write_timeout(Some(FILERUNNER_TIMEOUT))?;
+    Ok(stream)
+}
+
+/// Fetch a file from the untrusted filerunner
+pub fn get_file(endpoint: &str) -> std::io::Result<String> {
+    let mut stream = connect()?;
+
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        endpoint
+    );
+    stream.write_all(request.as_bytes())?;
+    stream.flush()?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+
+    let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+    let status = head.lines().next().unwrap_or("no response");
+    read_status(endpoint, status)?;
+
+    Ok(body.to_string())
+}
+
+/// Only a 404 means the filerunner has no such file. Any other failure
+/// is an error, so a failed read is never taken for a file never saved.
+fn read_status(endpoint: &str, status: &str) -> std::io::Result<()> {
+    if status.starts_with("HTTP/1.1 200") {
+        Ok(())
+    } else if status.starts_with("HTTP/1.1 404") {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("filerunner has no {}", endpoint),
+        ))
+    } else {
+        Err(std::io::Error::other(format!(
+            "filerunner failed to read {}: {}",
+            endpoint, status
+        )))
+    }
+}
+
+/// POST `body` to the filerunner, returning the status line and the response body
+fn post(endpoint: &str, body: &str) -> std::io::Result<(String, String)> {
+    let mut stream = connect()?;
+
+    let request = format!(
+        "POST {} HTTP/1.1\r\n\
+         Host: localhost\r\n\
+         Connection: close\r\n\
+         Content-Type: text/plain\r\n\
+         Content-Length: {}\r\n\
+         \r\n\
+         {}",
+        endpoint,
+        body.len(),
+        body
+    );
+
+    stream.write_all(request.as_bytes())?;
+    stream.flush()?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+
+    let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+    let status = head.lines().next().unwrap_or("no response").to_string();
+    Ok((status, body.to_string()))
+}
+
+/// Store a file on the untrusted filerunner. Only returns once the
+/// filerunner confirmed that the file was written.
+pub fn set_file(endpoint: &str, body: &str) -> std::io::Result<()> {
+    let (status, _) = post(endpoint, body)?;
+    if !status.starts_with("HTTP/1.1 200") {
+        return Err(std::io::Error::other(format!(
+            "filerunner failed to write {}: {}",
+            endpoint, status
+        )));
+    }
+
+    Ok(())
+}
+
+/// Send `body` to a service the filerunner runs for the relay, and return its answer
+pub fn call(endpoint: &str, body: &str) -> std::io::Result<String> {
+    let (status, body) = post(endpoint, body)?;
+    if !status.starts_with("HTTP/1.1 200") {
+        return Err(std::io::Error::other(format!(
+            "filerunner failed to answer {}: {}",
+            endpoint, status
+        )));
+    }
+
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_404_means_not_found() {
+        assert!(read_status("/get-blob/manifest", "HTTP/1.1 200 OK").is_ok());
+        let missing = read_status("/get-blob/manifest", "HTTP/1.1 404 Not Found").unwrap_err();
+        assert_eq!(missing.kind(), std::io::ErrorKind::NotFound);
+        let failed = read_status("/get-blob/manifest", "HTTP/1.1 500 Internal Server Error");
+        assert_ne!(failed.unwrap_err().kind(), std::io::ErrorKind::NotFound);
+        let failed = read_status("/get-blob/manifest", "no response");
+        assert_ne!(failed.unwrap_err().kind(), std::io::ErrorKind::NotFound);
+    }
+}
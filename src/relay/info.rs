@@ -0,0 +1,101 @@
+use std::io::Cursor;
+use tiny_http::{Header, Method, Request, Response};
+
+use crate::config::RelayConfig;
+use crate::server::Relay;
+
+/// Clients ask for the relay info (NIP-11) with this `Accept` header
+pub const INFO_CONTENT_TYPE: &str = "application/nostr+json";
+
+/// Whether `req` asks for the relay info document, or is a CORS
+/// preflight for it
+pub fn is_info_request(req: &Request) -> bool {
+    let path = req.url().split('?').next().unwrap_or_default();
+    if path != "/" {
+        return false;
+    }
+    match req.method() {
+        Method::Options => true,
+        Method::Get => req
+            .headers()
+            .iter()
+            .any(|header| header.field.equiv("Accept") && accepts_info(header.value.as_str())),
+        _ => false,
+    }
+}
+
+/// Whether an `Accept` header value includes the NIP-11 content type
+fn accepts_info(accept: &str) -> bool {
+    accept.split(',').any(|media_type| {
+        media_type.split(';').next().unwrap_or_default().trim() == INFO_CONTENT_TYPE
+    })
+}
+
+/// Value of the query parameter `name` in `url`
+fn query_param<'a>(url: &'a str, name: &str) -> Option<&'a str> {
+    let (_, query) = url.split_once('?')?;
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+/// Headers that let web clients read the relay info from any origin
+fn with_cors(response: Response<Cursor<Vec<u8>>>) -> Response<Cursor<Vec<u8>>> {
+    [
+        ("Access-Control-Allow-Origin", "*"),
+        ("Access-Control-Allow-Headers", "*"),
+        ("Access-Control-Allow-Methods", "GET, POST, OPTIONS"),
+    ]
+    .into_iter()
+    .fold(response, |response, (field, value)| {
+        response.with_header(Header::from_bytes(field, value).unwrap())
+    })
+}
+
+/// Serve the relay info document. The attestation is only included
+/// when the client sends a fresh `nonce` query parameter (64 hex chars),
+/// since a quote without one could be replayed by an impostor.
+pub fn handle(req: &Request, relay: &Relay, config: &RelayConfig) -> Response<Cursor<Vec<u8>>> {
+    if *req.method() == Method::Options {
+        return with_cors(Response::from_data(vec![]).with_status_code(204));
+    }
+
+    let attestation = query_param(req.url(), "nonce").and_then(|nonce| {
+        relay
+            .attestor
+            .attest(nonce)
+            .map_err(|e| println!("Failed to attest: {}", e))
+            .ok()
+    });
+    let mut info = config.info(&relay.db().moderation, attestation);
+    info.write_policy_plugin = relay.sends_events_out();
+
+    let content_type = Header::from_bytes("Content-Type", INFO_CONTENT_TYPE).unwrap();
+    let response = Response::from_data(serde_json::to_vec(&info).unwrap())
+        .with_header(content_type)
+        .with_status_code(200);
+    with_cors(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accepts_info() {
+        assert!(accepts_info("application/nostr+json"));
+        assert!(accepts_info("text/html, application/nostr+json; q=0.9"));
+        assert!(!accepts_info("application/json"));
+        assert!(!accepts_info("application/nostr+json+rpc"));
+    }
+
+    #[test]
+    fn test_query_param() {
+        assert_eq!(query_param("/?nonce=ab12", "nonce"), Some("ab12"));
+        assert_eq!(query_param("/?x=1&nonce=ab12", "nonce"), Some("ab12"));
+        assert_eq!(query_param("/?x=1", "nonce"), None);
+        assert_eq!(query_param("/", "nonce"), None);
+    }
+}
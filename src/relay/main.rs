@@ -1,67 +1,91 @@
-use core::message::{ClientMessage, RelayMessage};
+use attestation::Attestor;
+use autosave::Autosave;
+use config::{Overrides, RelayConfig};
 use db::DataHolder;
-use std::io::Cursor;
-use tiny_http::{Request, Response, Server};
+use policy::PluginPolicy;
+use server::Relay;
+use sweeper::Sweeper;
+use tiny_http::Server;
 
+mod admin;
+mod attestation;
+mod audit;
+mod auth;
+mod autosave;
+mod config;
 mod db;
+mod filerunner;
+mod info;
+mod management;
+mod manifest;
+mod policy;
+mod ratelimit;
+mod replay;
 mod sealing;
+mod server;
+mod shutdown;
+mod sweeper;
 
-/// The path to shutdown the server and SEAL the database.
-/// This is just an example and should not be used in production.
-/// Use CURL or similar to send a POST request to this path.
-const ADMIN_PATH_SHUTDOWN: &str = "/super-secret-admin-path-shutdown";
-/// The path to load a new database (UNSEAL).
-const ADMIN_PATH_LOAD: &str = "/super-secret-admin-path-load";
-
-/// Handle a request and return a response.
-fn nostrust_response(req: &mut Request, db: &mut DataHolder) -> Response<Cursor<Vec<u8>>> {
-    let path = req.url().to_string();
-    if path == ADMIN_PATH_SHUTDOWN {
-        println!("Shutting down and saving file...");
-        db.to_filerunner();
-        std::process::exit(0);
-    } else if path == ADMIN_PATH_LOAD {
-        println!("Loading new db...");
-        *db = DataHolder::from_filerunner();
-        return Response::from_string("OK").with_status_code(200);
+fn main() {
+    let overrides = Overrides::from_env().unwrap_or_else(|e| {
+        println!("{}", e);
+        println!("Usage: relay [--bind <address>] [--filerunner <address>]");
+        std::process::exit(2);
+    });
+    if let Some(filerunner) = &overrides.filerunner {
+        filerunner::set_server(filerunner);
     }
 
-    // read request body
-    let mut request_body_bytes = Vec::new();
-    req.as_reader()
-        .read_to_end(&mut request_body_bytes)
-        .unwrap();
-
-    // deserialize the event
-    let message: ClientMessage = serde_json::from_slice(&request_body_bytes).unwrap();
-    println!("Received: {:#?}", message);
-
-    // handle the message
-    let response = db.handle_message(message);
-    match response {
-        Some(clientresponse) => {
-            match clientresponse {
-                RelayMessage::Events(events) => {
-                    let response_body = serde_json::to_vec(&events).unwrap();
-                    Response::from_data(response_body).with_status_code(200)
-                }
-                RelayMessage::Info(info) => {
-                    let response_body = serde_json::to_vec(&info).unwrap();
-                    Response::from_data(response_body).with_status_code(200)
-                }
-            }
+    let mut config = RelayConfig::from_filerunner().unwrap_or_else(|e| {
+        println!("Failed to load config, using defaults: {}", e);
+        RelayConfig::default()
+    });
+    config.apply(&overrides);
+    filerunner::set_server(&config.network.filerunner);
+    let mut autosave = Autosave::new(&config.persistence);
+    let mut sweeper = Sweeper::new(&config.sweeper);
+    let (bind, workers) = (config.network.bind.clone(), config.network.workers);
+    let plugin = config.policy.plugin;
+    let mut db = match DataHolder::from_filerunner() {
+        Ok(Some(db)) => {
+            println!("Loaded saved db");
+            db
+        }
+        Ok(None) => {
+            println!("No saved db yet, starting empty");
+            DataHolder::default()
+        }
+        Err(e) => {
+            // saving the empty db would overwrite the saved one
+            println!("Failed to load saved db, not saving until it is reloaded: {}", e);
+            DataHolder::not_loaded()
         }
-        None => Response::from_string("OK").with_status_code(200),
+    };
+    let attestor = Attestor::with_transport_key(db.transport_key()).unwrap_or_else(|e| {
+        println!("Refusing to start: {}", e);
+        std::process::exit(1);
+    });
+    if cfg!(feature = "mock-attestation") {
+        println!("WARNING: attesting with the mock quote generator, whose quotes anyone can forge");
+    }
+    if admin::built_in_pubkeys().is_empty() {
+        println!("No admin keys built in, the admin API is disabled");
+    }
+    let mut relay = Relay::new(db, attestor, config);
+    // the plugin sees events in the clear, so the host's config can't turn
+    // it on for a build whose measurement says events stay in the enclave
+    if plugin && cfg!(feature = "write-policy-plugin") {
+        println!("Asking the write policy plugin about every event");
+        relay = relay.with_write_policy(PluginPolicy);
+    } else if plugin {
+        println!("Ignoring policy.plugin, this build keeps events inside the enclave");
     }
-}
 
-fn main() {
-    let (ip, port) = ("0.0.0.0", 8080);
-    let mut db = DataHolder::default();
+    println!("Running relay on {} with {} workers", bind, workers);
+    let server = Server::http(&bind).unwrap();
+    server::serve(&server, &relay, workers, &mut autosave, &mut sweeper);
+    // stop listening before the final save
+    drop(server);
 
-    let server = Server::http(format!("{}:{}", ip, port)).unwrap();
-    for mut request in server.incoming_requests() {
-        let resp = nostrust_response(&mut request, &mut db);
-        request.respond(resp).unwrap();
-    }
+    std::process::exit(shutdown::finish(&mut relay.db_mut()));
 }
@@ -0,0 +1,431 @@
+use core::event::Event;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Cursor;
+use std::net::IpAddr;
+use std::str::FromStr;
+use tiny_http::{Request, Response};
+
+use crate::admin;
+use crate::audit::AuditAction;
+use crate::config::{PolicyConfig, PolicyMode};
+use crate::db::{Blob, DataHolder};
+use crate::server::Relay;
+
+/// Content type of NIP-86 management requests
+pub const MANAGEMENT_CONTENT_TYPE: &str = "application/nostr+json+rpc";
+
+/// Methods of the NIP-86 relay management API we support
+const SUPPORTED_METHODS: [&str; 17] = [
+    "supportedmethods",
+    "banpubkey",
+    "listbannedpubkeys",
+    "allowpubkey",
+    "listallowedpubkeys",
+    "banevent",
+    "listbannedevents",
+    "changerelayname",
+    "allowkind",
+    "disallowkind",
+    "listallowedkinds",
+    "listdisallowedkinds",
+    "blockip",
+    "unblockip",
+    "listblockedips",
+    "setpolicymode",
+    "getpolicymode",
+];
+
+/// Moderation state set through the management API, sealed with the db.
+/// Maps hold the reason given for each entry.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct Moderation {
+    pub banned_pubkeys: BTreeMap<String, String>,
+    pub allowed_pubkeys: BTreeMap<String, String>,
+    pub banned_events: BTreeMap<String, String>,
+    /// If not empty, only these kinds are accepted
+    pub allowed_kinds: BTreeSet<usize>,
+    pub disallowed_kinds: BTreeSet<usize>,
+    pub blocked_ips: BTreeMap<String, String>,
+    pub relay_name: Option<String>,
+    /// Replaces the policy mode from the config when set
+    #[serde(default)]
+    pub policy_mode: Option<PolicyMode>,
+}
+
+impl Moderation {
+    /// Why `event` may not be stored, if it may not
+    pub fn rejects(&self, event: &Event) -> Option<String> {
+        if self.banned_pubkeys.contains_key(&event.pubkey) {
+            return Some("pubkey is banned".to_string());
+        }
+        if self.banned_events.contains_key(&event.id) {
+            return Some("event is banned".to_string());
+        }
+        if self.disallowed_kinds.contains(&event.kind)
+            || (!self.allowed_kinds.is_empty() && !self.allowed_kinds.contains(&event.kind))
+        {
+            return Some(format!("kind {} is not allowed", event.kind));
+        }
+        None
+    }
+
+    /// The policy mode in effect, set here or in the config
+    pub fn policy_mode(&self, policy: &PolicyConfig) -> PolicyMode {
+        self.policy_mode.unwrap_or(policy.mode)
+    }
+
+    pub fn is_ip_blocked(&self, ip: &str) -> bool {
+        self.blocked_ips.contains_key(ip)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RpcRequest {
+    pub method: String,
+    #[serde(default)]
+    pub params: Vec<Value>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct RpcResponse {
+    pub result: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl RpcResponse {
+    fn ok(result: Value) -> Self {
+        Self {
+            result,
+            error: None,
+        }
+    }
+
+    fn error(error: String) -> Self {
+        Self {
+            result: Value::Null,
+            error: Some(error),
+        }
+    }
+}
+
+/// String parameter at `index`
+fn param_str(params: &[Value], index: usize) -> Result<String, String> {
+    params
+        .get(index)
+        .and_then(Value::as_str)
+        .map(str::to_string)
+        .ok_or_else(|| format!("missing string parameter {}", index))
+}
+
+/// Hex parameter at `index`, such as a pubkey or event id
+fn param_hex(params: &[Value], index: usize) -> Result<String, String> {
+    let value = param_str(params, index)?.to_lowercase();
+    match hex::decode(&value) {
+        Ok(bytes) if !bytes.is_empty() => Ok(value),
+        _ => Err(format!("parameter {} must be hex", index)),
+    }
+}
+
+/// Optional reason parameter at `index`
+fn param_reason(params: &[Value], index: usize) -> String {
+    param_str(params, index).unwrap_or_default()
+}
+
+fn param_kind(params: &[Value], index: usize) -> Result<usize, String> {
+    params
+        .get(index)
+        .and_then(Value::as_u64)
+        .map(|kind| kind as usize)
+        .ok_or_else(|| format!("missing kind parameter {}", index))
+}
+
+/// An IP address parameter, written the way peer addresses are, so a
+/// typo is refused instead of blocking nothing
+fn param_ip(params: &[Value], index: usize) -> Result<String, String> {
+    let ip = param_str(params, index)?;
+    IpAddr::from_str(&ip)
+        .map(|ip| ip.to_string())
+        .map_err(|_| format!("parameter {} is not an IP address", index))
+}
+
+/// Run a management method against the db on behalf of the admin `pubkey`
+pub fn handle_rpc(db: &mut DataHolder, pubkey: &str, request: RpcRequest) -> RpcResponse {
+    let before = db.moderation.clone();
+    let response = match run_rpc(db, &request.method, &request.params) {
+        Ok(result) => RpcResponse::ok(result),
+        Err(e) => RpcResponse::error(e),
+    };
+    // listing methods leave the rules untouched, so don't seal them again
+    if db.moderation != before {
+        db.mark_dirty(Blob::Moderation);
+        let action = AuditAction::Admin {
+            pubkey: pubkey.to_string(),
+            action: request.method,
+        };
+        db.audit(chrono::Utc::now().timestamp() as usize, action);
+    }
+    response
+}
+
+fn run_rpc(db: &mut DataHolder, method: &str, params: &[Value]) -> Result<Value, String> {
+    let moderation = &mut db.moderation;
+    match method {
+        "supportedmethods" => Ok(json!(SUPPORTED_METHODS)),
+        "banpubkey" => {
+            let pubkey = param_hex(params, 0)?;
+            moderation.allowed_pubkeys.remove(&pubkey);
+            moderation
+                .banned_pubkeys
+                .insert(pubkey, param_reason(params, 1));
+            Ok(json!(true))
+        }
+        "listbannedpubkeys" => Ok(json!(moderation
+            .banned_pubkeys
+            .iter()
+            .map(|(pubkey, reason)| json!({ "pubkey": pubkey, "reason": reason }))
+            .collect::<Vec<_>>())),
+        "allowpubkey" => {
+            let pubkey = param_hex(params, 0)?;
+            moderation.banned_pubkeys.remove(&pubkey);
+            moderation
+                .allowed_pubkeys
+                .insert(pubkey, param_reason(params, 1));
+            Ok(json!(true))
+        }
+        "listallowedpubkeys" => Ok(json!(moderation
+            .allowed_pubkeys
+            .iter()
+            .map(|(pubkey, reason)| json!({ "pubkey": pubkey, "reason": reason }))
+            .collect::<Vec<_>>())),
+        "banevent" => {
+            let id = param_hex(params, 0)?;
+            moderation
+                .banned_events
+                .insert(id.clone(), param_reason(params, 1));
+            db.remove_event(&id);
+            Ok(json!(true))
+        }
+        "listbannedevents" => Ok(json!(moderation
+            .banned_events
+            .iter()
+            .map(|(id, reason)| json!({ "id": id, "reason": reason }))
+            .collect::<Vec<_>>())),
+        "changerelayname" => {
+            moderation.relay_name = Some(param_str(params, 0)?);
+            Ok(json!(true))
+        }
+        "allowkind" => {
+            let kind = param_kind(params, 0)?;
+            moderation.disallowed_kinds.remove(&kind);
+            moderation.allowed_kinds.insert(kind);
+            Ok(json!(true))
+        }
+        "disallowkind" => {
+            let kind = param_kind(params, 0)?;
+            moderation.allowed_kinds.remove(&kind);
+            moderation.disallowed_kinds.insert(kind);
+            Ok(json!(true))
+        }
+        "listallowedkinds" => Ok(json!(moderation.allowed_kinds)),
+        "listdisallowedkinds" => Ok(json!(moderation.disallowed_kinds)),
+        "blockip" => {
+            let ip = param_ip(params, 0)?;
+            moderation.blocked_ips.insert(ip, param_reason(params, 1));
+            Ok(json!(true))
+        }
+        "unblockip" => {
+            moderation.blocked_ips.remove(&param_ip(params, 0)?);
+            Ok(json!(true))
+        }
+        "listblockedips" => Ok(json!(moderation
+            .blocked_ips
+            .iter()
+            .map(|(ip, reason)| json!({ "ip": ip, "reason": reason }))
+            .collect::<Vec<_>>())),
+        // not part of NIP-86: switch between an open and a whitelisted
+        // (paid) relay, or back to the mode from the config with null
+        "setpolicymode" => {
+            let mode = params.first().cloned().unwrap_or(Value::Null);
+            moderation.policy_mode = serde_json::from_value(mode)
+                .map_err(|_| "parameter 0 must be \"open\", \"whitelist\" or null".to_string())?;
+            Ok(json!(true))
+        }
+        "getpolicymode" => Ok(json!(moderation.policy_mode)),
+        _ => Err(format!("unsupported method `{}`", method)),
+    }
+}
+
+/// Authorize and run a NIP-86 management request. Every attempt is logged.
+pub fn handle(req: &Request, body: &[u8], relay: &Relay) -> Response<Cursor<Vec<u8>>> {
+    let Some(pubkey) = admin::authorize_request(req, body, &mut relay.admin()) else {
+        return Response::from_string("unauthorized").with_status_code(401);
+    };
+
+    let request: RpcRequest = match serde_json::from_slice(body) {
+        Ok(request) => request,
+        Err(e) => {
+            let response = RpcResponse::error(format!("invalid request: {}", e));
+            return Response::from_data(serde_json::to_vec(&response).unwrap())
+                .with_status_code(400);
+        }
+    };
+    println!(
+        "Management: {} called {} {:?}",
+        pubkey, request.method, request.params
+    );
+
+    let response = handle_rpc(&mut relay.db_mut(), &pubkey, request);
+    Response::from_data(serde_json::to_vec(&response).unwrap()).with_status_code(200)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(db: &mut DataHolder, method: &str, params: Value) -> RpcResponse {
+        let request = serde_json::from_value(json!({ "method": method, "params": params }));
+        handle_rpc(db, "admin", request.unwrap())
+    }
+
+    fn event(pubkey: &str, kind: usize) -> Event {
+        Event {
+            id: "ab".repeat(32),
+            pubkey: pubkey.to_string(),
+            created_at: 0,
+            kind,
+            tags: vec![],
+            content: "content".to_string(),
+            sig: "sig".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_ban_and_allow_pubkey() {
+        let mut db = DataHolder::default();
+        let pubkey = "02".repeat(33);
+
+        let response = call(&mut db, "banpubkey", json!([pubkey, "spam"]));
+        assert_eq!(response, RpcResponse::ok(json!(true)));
+        assert!(db.moderation.rejects(&event(&pubkey, 1)).is_some());
+        assert_eq!(
+            call(&mut db, "listbannedpubkeys", json!([])).result,
+            json!([{ "pubkey": pubkey, "reason": "spam" }])
+        );
+
+        call(&mut db, "allowpubkey", json!([pubkey]));
+        assert!(db.moderation.rejects(&event(&pubkey, 1)).is_none());
+        assert_eq!(
+            call(&mut db, "listbannedpubkeys", json!([])).result,
+            json!([])
+        );
+    }
+
+    #[test]
+    fn test_kind_rules() {
+        let mut db = DataHolder::default();
+        let pubkey = "02".repeat(33);
+
+        call(&mut db, "disallowkind", json!([7]));
+        assert!(db.moderation.rejects(&event(&pubkey, 7)).is_some());
+        assert!(db.moderation.rejects(&event(&pubkey, 1)).is_none());
+
+        // once a kind is explicitly allowed, only allowed kinds pass
+        call(&mut db, "allowkind", json!([1]));
+        assert!(db.moderation.rejects(&event(&pubkey, 1)).is_none());
+        assert!(db.moderation.rejects(&event(&pubkey, 5)).is_some());
+    }
+
+    #[test]
+    fn test_ban_event() {
+        let mut db = DataHolder::default();
+        let event = event(&"02".repeat(33), 1);
+        db.events
+            .entry(event.pubkey.clone())
+            .or_default()
+            .push(event.clone());
+
+        call(&mut db, "banevent", json!([event.id, "illegal"]));
+
+        assert!(db.events[&event.pubkey].is_empty());
+        assert!(db.moderation.rejects(&event).is_some());
+    }
+
+    #[test]
+    fn test_change_relay_name_and_block_ip() {
+        let mut db = DataHolder::default();
+
+        call(&mut db, "changerelayname", json!(["Moderated Relay"]));
+        call(&mut db, "blockip", json!(["10.0.0.1", "abuse"]));
+
+        assert_eq!(db.moderation.relay_name.as_deref(), Some("Moderated Relay"));
+        assert!(db.moderation.is_ip_blocked("10.0.0.1"));
+
+        // stored the way peer addresses are written
+        call(&mut db, "blockip", json!(["2001:DB8:0:0::1"]));
+        assert!(db.moderation.is_ip_blocked("2001:db8::1"));
+        call(&mut db, "unblockip", json!(["2001:db8::0:1"]));
+        assert!(!db.moderation.is_ip_blocked("2001:db8::1"));
+    }
+
+    #[test]
+    fn test_set_policy_mode() {
+        let mut db = DataHolder::default();
+        let policy = PolicyConfig::default();
+
+        call(&mut db, "setpolicymode", json!(["whitelist"]));
+        assert_eq!(db.moderation.policy_mode(&policy), PolicyMode::Whitelist);
+        assert_eq!(
+            call(&mut db, "getpolicymode", json!([])).result,
+            json!("whitelist")
+        );
+        assert!(call(&mut db, "setpolicymode", json!(["paid"]))
+            .error
+            .is_some());
+
+        // null goes back to the mode from the config
+        call(&mut db, "setpolicymode", json!([null]));
+        assert_eq!(db.moderation.policy_mode, None);
+        assert_eq!(db.moderation.policy_mode(&policy), PolicyMode::Open);
+    }
+
+    #[test]
+    fn test_only_changes_mark_dirty() {
+        let mut db = DataHolder::default();
+
+        call(&mut db, "listblockedips", json!([]));
+        assert!(!db.is_dirty());
+        assert!(db.audit.records.is_empty());
+
+        call(&mut db, "blockip", json!(["10.0.0.1"]));
+        assert!(db.is_dirty());
+        assert_eq!(
+            db.audit.records[0].action,
+            AuditAction::Admin {
+                pubkey: "admin".to_string(),
+                action: "blockip".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_invalid_calls() {
+        let mut db = DataHolder::default();
+
+        assert!(call(&mut db, "banpubkey", json!([])).error.is_some());
+        assert!(call(&mut db, "banpubkey", json!(["not hex"]))
+            .error
+            .is_some());
+        assert!(call(&mut db, "allowkind", json!(["one"])).error.is_some());
+        assert!(call(&mut db, "blockip", json!(["10.0.0.01"]))
+            .error
+            .is_some());
+        assert!(call(&mut db, "unblockip", json!(["localhost"]))
+            .error
+            .is_some());
+        assert!(db.moderation.blocked_ips.is_empty());
+        assert!(call(&mut db, "dropdatabase", json!([])).error.is_some());
+    }
+}
@@ -0,0 +1,83 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+/// Where a sealed blob is stored and what it hashes to
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BlobRef {
+    /// Name the filerunner stores the blob under
+    pub file: String,
+    /// Hex SHA-256 of the sealed blob
+    pub hash: String,
+}
+
+/// Ties the separately sealed blobs to one save. It is sealed itself and
+/// written after the blobs it lists, so the host can't mix blobs from
+/// different saves, or roll a single blob back, without loading failing.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct Manifest {
+    /// Sealed state version of the save, as counted in the audit log
+    pub generation: u64,
+    /// The blobs of the save by name. Blobs that were never saved are missing.
+    pub blobs: BTreeMap<String, BlobRef>,
+}
+
+impl Manifest {
+    /// File to write a new version of the blob `name` to. Each blob has
+    /// two slots and the one listed here is never overwritten, so a save
+    /// that fails halfway leaves the saved state intact.
+    pub fn next_file(&self, name: &str) -> String {
+        let slot = match self.blobs.get(name) {
+            Some(blob) if blob.file.ends_with("-0") => 1,
+            _ => 0,
+        };
+        format!("{}-{}", name, slot)
+    }
+
+    /// Record that the blob `name` was written to `file` as `sealed`
+    pub fn insert(&mut self, name: &str, file: String, sealed: &str) {
+        let hash = hash(sealed);
+        self.blobs.insert(name.to_string(), BlobRef { file, hash });
+    }
+
+    /// Check that `sealed` is the version of the blob `name` listed here
+    pub fn check(&self, name: &str, sealed: &str) -> Result<()> {
+        let Some(blob) = self.blobs.get(name) else {
+            bail!("blob {} is not part of the saved state", name);
+        };
+        if blob.hash != hash(sealed) {
+            bail!(
+                "blob {} is not the one saved in generation {}",
+                name,
+                self.generation
+            );
+        }
+        Ok(())
+    }
+}
+
+fn hash(sealed: &str) -> String {
+    hex::encode(Sha256::digest(sealed.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slots_alternate_and_blobs_are_checked() {
+        let mut manifest = Manifest::default();
+        assert_eq!(manifest.next_file("events"), "events-0");
+
+        manifest.insert("events", "events-0".to_string(), "first");
+        assert_eq!(manifest.next_file("events"), "events-1");
+        assert!(manifest.check("events", "first").is_ok());
+        assert!(manifest.check("events", "older").is_err());
+        assert!(manifest.check("follows", "first").is_err());
+
+        manifest.insert("events", "events-1".to_string(), "second");
+        assert_eq!(manifest.next_file("events"), "events-0");
+        assert!(manifest.check("events", "first").is_err());
+    }
+}
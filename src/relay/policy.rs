@@ -0,0 +1,255 @@
+use anyhow::{bail, Result};
+use core::event::Event;
+use core::info::Kinds;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+
+use crate::config::{PolicyMode, RelayConfig};
+use crate::filerunner;
+use crate::management::Moderation;
+
+/// Prefixes clients understand at the start of an `OK` message
+const MESSAGE_PREFIXES: [&str; 8] = [
+    "duplicate:",
+    "pow:",
+    "blocked:",
+    "rate-limited:",
+    "invalid:",
+    "restricted:",
+    "auth-required:",
+    "error:",
+];
+
+/// What a write policy decided about an event
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    Accept,
+    /// Refuse the event with an `OK` message such as `blocked: spam`
+    Reject(String),
+    /// Drop the event but tell the client it was stored, so spammers don't
+    /// learn what gets through. The reason is only logged.
+    ShadowReject(String),
+}
+
+/// Custom acceptance logic, asked about every new event before it is
+/// stored. Erasure requests are always honoured and never asked about.
+pub trait WritePolicy: Send + Sync {
+    /// `source` is the peer that sent the event, if known
+    fn check(&self, event: &Event, source: Option<SocketAddr>) -> Verdict;
+
+    /// Whether the policy sends events out of the enclave
+    fn leaves_enclave(&self) -> bool {
+        false
+    }
+}
+
+/// Line a plugin gets for each event, as in strfry
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PluginRequest<'a> {
+    r#type: &'static str,
+    event: &'a Event,
+    received_at: usize,
+    source_type: &'static str,
+    source_info: String,
+}
+
+/// Line a plugin answers with
+#[derive(Deserialize)]
+struct PluginResponse {
+    id: String,
+    action: PluginAction,
+    #[serde(default)]
+    msg: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+enum PluginAction {
+    Accept,
+    Reject,
+    ShadowReject,
+}
+
+/// Asks an external plugin process about every event, one JSON line each
+/// way. The enclave can't start processes, so the filerunner runs the
+/// plugin, which therefore sees events in the clear.
+pub struct PluginPolicy;
+
+impl PluginPolicy {
+    fn ask(&self, event: &Event, source: Option<SocketAddr>) -> Result<Verdict> {
+        let (source_type, source_info) = match source {
+            Some(SocketAddr::V4(addr)) => ("IP4", addr.ip().to_string()),
+            Some(SocketAddr::V6(addr)) => ("IP6", addr.ip().to_string()),
+            None => ("Unknown", String::new()),
+        };
+        let request = PluginRequest {
+            r#type: "new",
+            event,
+            received_at: chrono::Utc::now().timestamp() as usize,
+            source_type,
+            source_info,
+        };
+
+        let answer = filerunner::call("/write-policy", &serde_json::to_string(&request)?)?;
+        parse_answer(&answer, &event.id)
+    }
+}
+
+impl WritePolicy for PluginPolicy {
+    fn check(&self, event: &Event, source: Option<SocketAddr>) -> Verdict {
+        self.ask(event, source).unwrap_or_else(|e| {
+            // refuse rather than let everything through while the plugin is down
+            println!("Write policy plugin failed: {}", e);
+            Verdict::Reject("error: could not check the event".to_string())
+        })
+    }
+
+    fn leaves_enclave(&self) -> bool {
+        true
+    }
+}
+
+/// Verdict from a plugin's answer about the event `id`
+fn parse_answer(answer: &str, id: &str) -> Result<Verdict> {
+    let response: PluginResponse = serde_json::from_str(answer.trim())?;
+    if response.id != id {
+        bail!("answer is for event {}, not {}", response.id, id);
+    }
+    Ok(match response.action {
+        PluginAction::Accept => Verdict::Accept,
+        PluginAction::Reject if response.msg.is_empty() => {
+            Verdict::Reject("blocked: refused by the write policy".to_string())
+        }
+        PluginAction::Reject if MESSAGE_PREFIXES.iter().any(|p| response.msg.starts_with(p)) => {
+            Verdict::Reject(response.msg)
+        }
+        PluginAction::Reject => Verdict::Reject(format!("blocked: {}", response.msg)),
+        PluginAction::ShadowReject => Verdict::ShadowReject(response.msg),
+    })
+}
+
+/// Why `event` may not be stored, if it may not. The rules from the config
+/// and those set through the management API both apply: a pubkey or kind
+/// refused by either is refused, and in whitelist mode a pubkey allowed
+/// by either may post.
+pub fn rejects(config: &RelayConfig, moderation: &Moderation, event: &Event) -> Option<String> {
+    if let Some(reason) = moderation.rejects(event) {
+        return Some(reason);
+    }
+
+    let policy = &config.policy;
+    let listed = |pubkeys: &[String]| {
+        pubkeys
+            .iter()
+            .any(|pubkey| pubkey.eq_ignore_ascii_case(&event.pubkey))
+    };
+    if listed(&policy.blocked_pubkeys) {
+        return Some("pubkey is blocked".to_string());
+    }
+    let covers = |kinds: &[Kinds]| kinds.iter().any(|kinds| kinds.contains(event.kind));
+    if covers(&policy.blocked_kinds)
+        || (!policy.allowed_kinds.is_empty() && !covers(&policy.allowed_kinds))
+    {
+        return Some(format!("kind {} is not allowed", event.kind));
+    }
+
+    if moderation.policy_mode(policy) == PolicyMode::Whitelist
+        && !listed(&policy.allowed_pubkeys)
+        && !moderation.allowed_pubkeys.contains_key(&event.pubkey)
+    {
+        return Some(match &config.info.payments_url {
+            Some(url) => format!("pubkey has not paid for this relay, see {}", url),
+            None => "pubkey is not on the whitelist".to_string(),
+        });
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(pubkey: &str, kind: usize) -> Event {
+        Event {
+            id: "ab".repeat(32),
+            pubkey: pubkey.to_string(),
+            created_at: 0,
+            kind,
+            tags: vec![],
+            content: "content".to_string(),
+            sig: "sig".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_config_and_moderation_rules_combine() {
+        let (alice, bob) = ("02".repeat(33), "03".repeat(33));
+        let mut config = RelayConfig::default();
+        config.policy.blocked_pubkeys = vec![bob.to_uppercase()];
+        config.policy.blocked_kinds = vec![Kinds::Range([20000, 29999])];
+        let mut moderation = Moderation::default();
+        moderation.disallowed_kinds.insert(7);
+
+        assert!(rejects(&config, &moderation, &event(&alice, 1)).is_none());
+        assert!(rejects(&config, &moderation, &event(&bob, 1)).is_some());
+        assert!(rejects(&config, &moderation, &event(&alice, 7)).is_some());
+        assert!(rejects(&config, &moderation, &event(&alice, 20001)).is_some());
+
+        config.policy.allowed_kinds = vec![Kinds::Kind(1)];
+        assert!(rejects(&config, &moderation, &event(&alice, 1)).is_none());
+        assert!(rejects(&config, &moderation, &event(&alice, 30023)).is_some());
+    }
+
+    #[test]
+    fn test_parse_plugin_answer() {
+        let answer = |action: &str, msg: &str| {
+            let line = serde_json::json!({ "id": "ab", "action": action, "msg": msg });
+            parse_answer(&format!("{}\n", line), "ab").unwrap()
+        };
+        assert_eq!(answer("accept", ""), Verdict::Accept);
+        assert_eq!(
+            answer("reject", "rate-limited: too many links"),
+            Verdict::Reject("rate-limited: too many links".to_string())
+        );
+        assert_eq!(
+            answer("reject", "spam"),
+            Verdict::Reject("blocked: spam".to_string())
+        );
+        assert_eq!(
+            answer("shadowReject", "spam"),
+            Verdict::ShadowReject("spam".to_string())
+        );
+
+        assert!(parse_answer(r#"{"id":"cd","action":"accept"}"#, "ab").is_err());
+        assert!(parse_answer(r#"{"id":"ab","action":"maybe"}"#, "ab").is_err());
+    }
+
+    #[test]
+    fn test_whitelist_mode() {
+        let (alice, bob, carol) = ("02".repeat(33), "03".repeat(33), "04".repeat(33));
+        let mut config = RelayConfig::default();
+        config.policy.mode = PolicyMode::Whitelist;
+        config.policy.allowed_pubkeys = vec![alice.clone()];
+        let mut moderation = Moderation::default();
+        moderation
+            .allowed_pubkeys
+            .insert(bob.clone(), "paid".to_string());
+
+        assert!(rejects(&config, &moderation, &event(&alice, 1)).is_none());
+        assert!(rejects(&config, &moderation, &event(&bob, 1)).is_none());
+        assert_eq!(
+            rejects(&config, &moderation, &event(&carol, 1)).as_deref(),
+            Some("pubkey is not on the whitelist")
+        );
+
+        config.info.payments_url = Some("https://pay.example".to_string());
+        assert!(rejects(&config, &moderation, &event(&carol, 1))
+            .unwrap()
+            .contains("https://pay.example"));
+
+        // an admin can open the relay again without touching the config
+        moderation.policy_mode = Some(PolicyMode::Open);
+        assert!(rejects(&config, &moderation, &event(&carol, 1)).is_none());
+    }
+}
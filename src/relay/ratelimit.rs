@@ -0,0 +1,239 @@
+use core::event::Event;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::time::Instant;
+
+use crate::config::{KindRate, Rate, RateLimitsConfig};
+
+/// Tokens left for one key, refilled lazily whenever the key is seen
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, rate: Rate, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        let burst = rate.burst.max(1) as f64;
+        self.tokens = (self.tokens + elapsed * rate.per_minute as f64 / 60.0).min(burst);
+        self.updated = now;
+    }
+
+    fn is_full(&self, rate: Rate) -> bool {
+        self.tokens >= rate.burst.max(1) as f64
+    }
+}
+
+/// A token bucket per key
+struct Buckets<K> {
+    buckets: HashMap<K, Bucket>,
+}
+
+impl<K> Default for Buckets<K> {
+    fn default() -> Self {
+        Self {
+            buckets: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Eq + Hash> Buckets<K> {
+    /// Take a token from the bucket of `key`. False if it is empty.
+    fn take(&mut self, key: K, rate: Rate, now: Instant) -> bool {
+        if rate.is_unlimited() {
+            return true;
+        }
+        let bucket = self.buckets.entry(key).or_insert(Bucket {
+            tokens: rate.burst.max(1) as f64,
+            updated: now,
+        });
+        bucket.refill(rate, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Forget buckets that filled up again, a new one behaves the same
+    fn prune(&mut self, rate: impl Fn(&K) -> Rate, now: Instant) {
+        self.buckets.retain(|key, bucket| {
+            let rate = rate(key);
+            bucket.refill(rate, now);
+            !rate.is_unlimited() && !bucket.is_full(rate)
+        });
+    }
+}
+
+/// How many messages were refused for going too fast, reported in the stats
+#[derive(Serialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct RateLimited {
+    pub events: u64,
+    pub reqs: u64,
+    pub connections: u64,
+}
+
+/// Who a REQ counts against. Each request can come from a new source
+/// port, so it is the user the session authenticated as, or else the IP.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ReqSource {
+    User(String),
+    Ip(IpAddr),
+}
+
+/// Token bucket rate limits on events per pubkey (and rule), REQs per
+/// user and connections per IP.
+#[derive(Default)]
+pub struct RateLimiter {
+    /// Keyed by pubkey and the index of the kind rule, if any
+    events: Buckets<(String, Option<usize>)>,
+    reqs: Buckets<ReqSource>,
+    connections: Buckets<IpAddr>,
+    counters: RateLimited,
+}
+
+impl RateLimiter {
+    /// Whether `event` may be stored. Only call this once the signature
+    /// was checked, or anyone could use up the tokens of another pubkey.
+    pub fn allow_event(&mut self, event: &Event, config: &RateLimitsConfig, now: Instant) -> bool {
+        let (rule, rate) = config.for_kind(event.kind);
+        let allowed = self.events.take((event.pubkey.clone(), rule), rate, now);
+        if !allowed {
+            self.counters.events += 1;
+        }
+        allowed
+    }
+
+    /// Whether `source` may open another subscription
+    pub fn allow_req(
+        &mut self,
+        source: ReqSource,
+        config: &RateLimitsConfig,
+        now: Instant,
+    ) -> bool {
+        let allowed = self.reqs.take(source, config.reqs_per_user, now);
+        if !allowed {
+            self.counters.reqs += 1;
+        }
+        allowed
+    }
+
+    /// Whether `ip` may send another request
+    pub fn allow_connection(
+        &mut self,
+        ip: IpAddr,
+        config: &RateLimitsConfig,
+        now: Instant,
+    ) -> bool {
+        let allowed = self.connections.take(ip, config.connections_per_ip, now);
+        if !allowed {
+            self.counters.connections += 1;
+        }
+        allowed
+    }
+
+    /// Drop the buckets that are full, so idle keys don't take up memory
+    pub fn prune(&mut self, config: &RateLimitsConfig, now: Instant) {
+        self.events.prune(
+            // the rules may have changed since, after a config reload
+            |(_, rule)| {
+                rule.and_then(|index| config.kinds.get(index))
+                    .map_or(config.events, KindRate::rate)
+            },
+            now,
+        );
+        self.reqs.prune(|_| config.reqs_per_user, now);
+        self.connections.prune(|_| config.connections_per_ip, now);
+    }
+
+    pub fn counters(&self) -> RateLimited {
+        self.counters
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::info::Kinds;
+    use std::time::Duration;
+
+    fn event(pubkey: &str, kind: usize) -> Event {
+        Event {
+            id: "id".to_string(),
+            pubkey: pubkey.to_string(),
+            created_at: 0,
+            kind,
+            tags: vec![],
+            content: "".to_string(),
+            sig: "".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_bucket_refills() {
+        let config = RateLimitsConfig {
+            events: Rate {
+                per_minute: 60,
+                burst: 2,
+            },
+            ..RateLimitsConfig::default()
+        };
+        let mut limiter = RateLimiter::default();
+        let start = Instant::now();
+
+        assert!(limiter.allow_event(&event("a", 1), &config, start));
+        assert!(limiter.allow_event(&event("a", 1), &config, start));
+        assert!(!limiter.allow_event(&event("a", 1), &config, start));
+        // other pubkeys have their own bucket
+        assert!(limiter.allow_event(&event("b", 1), &config, start));
+        // one token per second comes back
+        let later = start + Duration::from_secs(1);
+        assert!(limiter.allow_event(&event("a", 1), &config, later));
+        assert!(!limiter.allow_event(&event("a", 1), &config, later));
+
+        assert_eq!(limiter.counters().events, 2);
+    }
+
+    #[test]
+    fn test_kind_rules_have_their_own_buckets() {
+        let config = RateLimitsConfig {
+            events: Rate {
+                per_minute: 60,
+                burst: 1,
+            },
+            kinds: vec![KindRate {
+                kinds: vec![Kinds::Kind(7)],
+                per_minute: 0,
+                burst: 0,
+            }],
+            ..RateLimitsConfig::default()
+        };
+        let mut limiter = RateLimiter::default();
+        let now = Instant::now();
+
+        assert!(limiter.allow_event(&event("a", 1), &config, now));
+        assert!(!limiter.allow_event(&event("a", 1), &config, now));
+        // reactions are unlimited
+        for _ in 0..100 {
+            assert!(limiter.allow_event(&event("a", 7), &config, now));
+        }
+    }
+
+    #[test]
+    fn test_prune_forgets_full_buckets() {
+        let config = RateLimitsConfig::default();
+        let mut limiter = RateLimiter::default();
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let start = Instant::now();
+
+        assert!(limiter.allow_connection(ip, &config, start));
+        limiter.prune(&config, start);
+        assert_eq!(limiter.connections.buckets.len(), 1);
+        limiter.prune(&config, start + Duration::from_secs(60));
+        assert!(limiter.connections.buckets.is_empty());
+    }
+}
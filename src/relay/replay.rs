@@ -0,0 +1,65 @@
+use anyhow::{bail, Result};
+use core::channel::COUNTER_WINDOW;
+use std::collections::HashMap;
+
+/// Refuses encrypted requests the host recorded and plays again. Counters
+/// must rise within a session, and be close to the clock, so after a
+/// restart only requests from the last window could be replayed.
+#[derive(Default)]
+pub struct ReplayGuard {
+    /// Last counter accepted from each session
+    counters: HashMap<String, u64>,
+}
+
+impl ReplayGuard {
+    /// Accept `counter` for `session` if it is near `now` and above the session's last one
+    pub fn check(&mut self, session: &str, counter: u64, now: u64) -> Result<()> {
+        if counter.abs_diff(now) > window() {
+            bail!("request counter is too old or too far ahead");
+        }
+        if self
+            .counters
+            .get(session)
+            .is_some_and(|last| counter <= *last)
+        {
+            bail!("request was already received");
+        }
+        self.counters.insert(session.to_string(), counter);
+        Ok(())
+    }
+
+    /// Forget sessions whose last counter is out of the window, and so
+    /// below any counter that could still be accepted
+    pub fn prune(&mut self, now: u64) {
+        self.counters
+            .retain(|_, last| now.saturating_sub(*last) <= window());
+    }
+}
+
+fn window() -> u64 {
+    COUNTER_WINDOW.as_micros() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counters_must_rise_and_be_recent() {
+        let mut guard = ReplayGuard::default();
+        let now = 1_000_000_000_000;
+
+        assert!(guard.check("a", now, now).is_ok());
+        assert!(guard.check("a", now, now).is_err());
+        assert!(guard.check("a", now - 1, now).is_err());
+        assert!(guard.check("a", now + 1, now).is_ok());
+        // sessions count on their own
+        assert!(guard.check("b", now, now).is_ok());
+
+        assert!(guard.check("c", now - window() - 1, now).is_err());
+        assert!(guard.check("c", now + window() + 1, now).is_err());
+
+        guard.prune(now + window() + 2);
+        assert!(guard.counters.is_empty());
+    }
+}
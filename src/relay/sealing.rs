@@ -1,7 +1,7 @@
 // Code in this file is based on the `sealing` example from the `sgx-isa` crate.
 // https://github.com/fortanix/rust-sgx
 
-use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::aead::{Aead, KeyInit, Payload};
 use aes_gcm::{Aes128Gcm, Nonce};
 use anyhow::Result;
 use base64::{engine::general_purpose, Engine};
@@ -10,15 +10,77 @@ use rand::Rng;
 use serde::{Deserialize, Serialize};
 use sgx_isa::{ErrorCode, Keyname, Keypolicy, Keyrequest, Report};
 
-const SEAL_KEY_LABEL: [u8; 16] = [0; 16];
+/// Label used before data types were sealed separately. Only needed to
+/// unseal blobs written by older versions of the relay.
+const LEGACY_SEAL_KEY_LABEL: [u8; 16] = [0; 16];
 
-pub fn encrypt_string(key: &[u8; 16], plaintext: String) -> Result<String> {
+/// Marks a blob as a sealed envelope written by this relay.
+const ENVELOPE_MAGIC: &str = "NOSTRUST-SEAL";
+/// Current envelope format. Bump this when the header or payload layout
+/// changes, and keep `unseal` able to read the older versions.
+const ENVELOPE_VERSION: u16 = 1;
+
+/// What kind of data an envelope carries. Part of the authenticated
+/// header, so a blob can't be unsealed as a different type of data.
+/// Each type is sealed under its own label, and so its own derived key.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum PayloadType {
+    /// The whole `DataHolder` in one blob, as written by older relays.
+    Database,
+    Events,
+    FollowGraph,
+    KeyTable,
+    AuditLog,
+    Config,
+    /// Lists the blobs of a save and their hashes
+    Manifest,
+}
+
+impl PayloadType {
+    /// The key derivation label for this type of data.
+    pub fn label(&self) -> [u8; 16] {
+        match self {
+            PayloadType::Database => LEGACY_SEAL_KEY_LABEL,
+            PayloadType::Events => *b"nostrust/events\0",
+            PayloadType::FollowGraph => *b"nostrust/follows",
+            PayloadType::KeyTable => *b"nostrust/keys\0\0\0",
+            PayloadType::AuditLog => *b"nostrust/audit\0\0",
+            PayloadType::Config => *b"nostrust/config\0",
+            PayloadType::Manifest => *b"nostrust/manifst",
+        }
+    }
+}
+
+/// Self-describing header of a sealed envelope. The whole header is
+/// bound to the ciphertext as AES-GCM associated data.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EnvelopeHeader {
+    magic: String,
+    version: u16,
+    keypolicy: u16,
+    seal_data: SealData,
+    label: [u8; 16],
+    payload_type: PayloadType,
+}
+
+/// A sealed blob: the header plus base64(nonce || ciphertext).
+#[derive(Serialize, Deserialize, Debug)]
+struct Envelope {
+    header: EnvelopeHeader,
+    payload: String,
+}
+
+pub fn encrypt_string(key: &[u8; 16], plaintext: String, aad: &[u8]) -> Result<String> {
     let cipher = Aes128Gcm::new_from_slice(key)?;
     let nonce: [u8; 12] = rand::thread_rng().gen();
     let nonce = Nonce::from_slice(&nonce);
 
+    let payload = Payload {
+        msg: plaintext.as_bytes(),
+        aad,
+    };
     let ciphertext = cipher
-        .encrypt(nonce, plaintext.as_bytes())
+        .encrypt(nonce, payload)
         .map_err(|_| anyhow::anyhow!("encryption failed"))?;
 
     let mut result = Vec::new();
@@ -27,13 +89,20 @@ pub fn encrypt_string(key: &[u8; 16], plaintext: String) -> Result<String> {
     Ok(general_purpose::STANDARD.encode(&result))
 }
 
-pub fn decrypt_string(key: &[u8; 16], ciphertext: String) -> Result<String> {
+pub fn decrypt_string(key: &[u8; 16], ciphertext: String, aad: &[u8]) -> Result<String> {
     let cipher = Aes128Gcm::new_from_slice(key)?;
     let ciphertext = general_purpose::STANDARD.decode(ciphertext.as_bytes())?;
+    if ciphertext.len() < 12 {
+        anyhow::bail!("ciphertext too short");
+    }
 
     let nonce = Nonce::from_slice(&ciphertext[..12]);
+    let payload = Payload {
+        msg: &ciphertext[12..],
+        aad,
+    };
     let plaintext = cipher
-        .decrypt(nonce, &ciphertext[12..])
+        .decrypt(nonce, payload)
         .map_err(|_| anyhow::anyhow!("decryption failed"))?;
 
     Ok(String::from_utf8(plaintext)?)
@@ -42,7 +111,7 @@ pub fn decrypt_string(key: &[u8; 16], ciphertext: String) -> Result<String> {
 /// Information about how the sealing key was derived. This
 /// should be stored alongside the sealed data, so that the enclave
 /// can rederive the same key later.
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct SealData {
     rand: [u8; 16],
     isvsvn: u16,
@@ -51,18 +120,22 @@ pub struct SealData {
 
 /// Derive a sealing key for the current enclave given `label` and
 /// `seal_data`.
-fn egetkey(seal_data: &SealData) -> Result<[u8; 16], ErrorCode> {
+fn egetkey(
+    label: [u8; 16],
+    keypolicy: Keypolicy,
+    seal_data: &SealData,
+) -> Result<[u8; 16], ErrorCode> {
     // Key ID is combined from fixed label and random data
     let mut keyid = [0; 32];
     {
         let (label_dst, rand_dst) = keyid.split_at_mut(16);
-        label_dst.copy_from_slice(&SEAL_KEY_LABEL);
+        label_dst.copy_from_slice(&label);
         rand_dst.copy_from_slice(&seal_data.rand);
     }
 
     Keyrequest {
         keyname: Keyname::Seal as _,
-        keypolicy: Keypolicy::MRENCLAVE,
+        keypolicy,
         isvsvn: seal_data.isvsvn,
         cpusvn: seal_data.cpusvn,
         attributemask: [!0; 2],
@@ -81,7 +154,7 @@ fn egetkey(seal_data: &SealData) -> Result<[u8; 16], ErrorCode> {
 /// different types of data, make sure to pass a different `label`.
 /// The returned `SealData` should be stored alongside the
 /// ciphertext to make sure the data can be unsealed again later.
-pub fn seal_key() -> ([u8; 16], SealData) {
+pub fn seal_key(label: [u8; 16]) -> ([u8; 16], SealData) {
     let report = Report::for_self();
     let seal_data = SealData {
         // Generate fresh randomness for each sealing operation.
@@ -93,7 +166,10 @@ pub fn seal_key() -> ([u8; 16], SealData) {
 
     // EGETKEY should never error here because we used the
     // information from `Report::for_self`.
-    (egetkey(&seal_data).unwrap(), seal_data)
+    (
+        egetkey(label, Keypolicy::MRENCLAVE, &seal_data).unwrap(),
+        seal_data,
+    )
 }
 
 /// Get a key for unsealing data.
@@ -103,9 +179,81 @@ pub fn seal_key() -> ([u8; 16], SealData) {
 /// Pass in the same `label` that was used to get the sealing key,
 /// and pass in the `seal_data` that was returned when obtaining the
 /// sealing key.
-pub fn unseal_key(seal_data: SealData) -> Result<[u8; 16], ErrorCode> {
+pub fn unseal_key(label: [u8; 16], seal_data: SealData) -> Result<[u8; 16], ErrorCode> {
     // let report = Report::for_self();
-    egetkey(&seal_data)
+    egetkey(label, Keypolicy::MRENCLAVE, &seal_data)
+}
+
+/// Whether `sealed` looks like an envelope produced by `seal`, as opposed
+/// to a bare ciphertext written before the envelope format existed.
+pub fn is_envelope(sealed: &str) -> bool {
+    serde_json::from_str::<Envelope>(sealed)
+        .map(|envelope| envelope.header.magic == ENVELOPE_MAGIC)
+        .unwrap_or(false)
+}
+
+/// Seal `plaintext` into a versioned envelope under a fresh sealing key.
+pub fn seal(payload_type: PayloadType, plaintext: String) -> Result<String> {
+    let label = payload_type.label();
+    let (key, seal_data) = seal_key(label);
+    let header = EnvelopeHeader {
+        magic: ENVELOPE_MAGIC.to_string(),
+        version: ENVELOPE_VERSION,
+        keypolicy: Keypolicy::MRENCLAVE.bits(),
+        seal_data,
+        label,
+        payload_type,
+    };
+
+    let aad = serde_json::to_vec(&header)?;
+    let payload = encrypt_string(&key, plaintext, &aad)?;
+
+    Ok(serde_json::to_string(&Envelope { header, payload })?)
+}
+
+/// Unseal an envelope produced by `seal`, checking that it holds data
+/// of the `expected` type. Any change to the header or payload, or a
+/// payload moved over from another envelope, fails authentication.
+pub fn unseal(expected: PayloadType, sealed: &str) -> Result<String> {
+    let Envelope { header, payload } = serde_json::from_str(sealed)?;
+
+    if header.magic != ENVELOPE_MAGIC {
+        anyhow::bail!("not a sealed envelope");
+    }
+    match header.version {
+        ENVELOPE_VERSION => {}
+        version => anyhow::bail!("unsupported envelope version {}", version),
+    }
+    if header.payload_type != expected {
+        anyhow::bail!(
+            "envelope holds {:?}, expected {:?}",
+            header.payload_type,
+            expected
+        );
+    }
+    if header.label != expected.label() {
+        anyhow::bail!("envelope was sealed under the wrong label");
+    }
+
+    // the header comes from the host: a key bound to the signer only
+    // would open envelopes sealed by any enclave from the same signer
+    if header.keypolicy != Keypolicy::MRENCLAVE.bits() {
+        anyhow::bail!("envelope was not sealed to this enclave's measurement");
+    }
+    let key = egetkey(header.label, Keypolicy::MRENCLAVE, &header.seal_data)
+        .map_err(|e| anyhow::anyhow!("failed to derive unsealing key: {:?}", e))?;
+
+    let aad = serde_json::to_vec(&header)?;
+    decrypt_string(&key, payload, &aad)
+}
+
+/// Unseal a blob written before the envelope format, where the
+/// `SealData` was stored in a separate file and nothing was bound
+/// to the ciphertext.
+pub fn unseal_legacy(sealed: String, seal_data: SealData) -> Result<String> {
+    let key = unseal_key(LEGACY_SEAL_KEY_LABEL, seal_data)
+        .map_err(|e| anyhow::anyhow!("failed to derive unsealing key: {:?}", e))?;
+    decrypt_string(&key, sealed, &[])
 }
 
 #[cfg(test)]
@@ -114,8 +262,81 @@ mod tests {
 
     #[test]
     fn test_seal_unseal() {
-        let (seal_key, seal_data) = seal_key();
-        let unseal_key = unseal_key(seal_data).unwrap();
+        let label = PayloadType::Events.label();
+        let (seal_key, seal_data) = seal_key(label);
+        let unseal_key = unseal_key(label, seal_data).unwrap();
         assert_eq!(seal_key, unseal_key);
     }
+
+    #[test]
+    fn test_labels_distinct() {
+        let types = [
+            PayloadType::Database,
+            PayloadType::Events,
+            PayloadType::FollowGraph,
+            PayloadType::KeyTable,
+            PayloadType::AuditLog,
+            PayloadType::Config,
+            PayloadType::Manifest,
+        ];
+        for (i, a) in types.iter().enumerate() {
+            for b in &types[i + 1..] {
+                assert_ne!(a.label(), b.label());
+            }
+        }
+    }
+
+    #[test]
+    fn test_envelope_wrong_type_rejected() {
+        let sealed = seal(PayloadType::Events, "secret".to_string()).unwrap();
+        assert!(unseal(PayloadType::FollowGraph, &sealed).is_err());
+    }
+
+    #[test]
+    fn test_envelope_roundtrip() {
+        let sealed = seal(PayloadType::Database, "secret".to_string()).unwrap();
+        assert!(is_envelope(&sealed));
+        assert_eq!(unseal(PayloadType::Database, &sealed).unwrap(), "secret");
+    }
+
+    #[test]
+    fn test_envelope_header_tamper_detected() {
+        let sealed = seal(PayloadType::Database, "secret".to_string()).unwrap();
+        let mut envelope: Envelope = serde_json::from_str(&sealed).unwrap();
+        envelope.header.seal_data.isvsvn += 1;
+        let tampered = serde_json::to_string(&envelope).unwrap();
+
+        assert!(unseal(PayloadType::Database, &tampered).is_err());
+    }
+
+    #[test]
+    fn test_envelope_signer_policy_rejected() {
+        let sealed = seal(PayloadType::Database, "secret".to_string()).unwrap();
+        let mut envelope: Envelope = serde_json::from_str(&sealed).unwrap();
+        envelope.header.keypolicy = Keypolicy::MRSIGNER.bits();
+        let relabeled = serde_json::to_string(&envelope).unwrap();
+
+        let error = unseal(PayloadType::Database, &relabeled).unwrap_err();
+        assert!(error.to_string().contains("measurement"));
+    }
+
+    #[test]
+    fn test_envelope_payload_swap_detected() {
+        let first = seal(PayloadType::Database, "first".to_string()).unwrap();
+        let second = seal(PayloadType::Database, "second".to_string()).unwrap();
+
+        let mut mixed: Envelope = serde_json::from_str(&first).unwrap();
+        let other: Envelope = serde_json::from_str(&second).unwrap();
+        mixed.payload = other.payload;
+        let mixed = serde_json::to_string(&mixed).unwrap();
+
+        assert!(unseal(PayloadType::Database, &mixed).is_err());
+    }
+
+    #[test]
+    fn test_legacy_blob_is_not_envelope() {
+        let (key, _) = seal_key(LEGACY_SEAL_KEY_LABEL);
+        let legacy = encrypt_string(&key, "secret".to_string(), &[]).unwrap();
+        assert!(!is_envelope(&legacy));
+    }
 }
@@ -0,0 +1,1166 @@
+use anyhow::{anyhow, Result};
+use core::channel::counter_now;
+use core::event::Event;
+use core::message::{ClientMessage, RelayMessage, AUTH_KIND};
+use serde::Serialize;
+use std::io::{Cursor, Read};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+use tiny_http::{Request, Response, Server};
+
+use crate::admin::{self, Admin};
+use crate::attestation::Attestor;
+use crate::auth::Authenticator;
+use crate::autosave::Autosave;
+use crate::config::RelayConfig;
+use crate::db::{self, DataHolder, Stats};
+use crate::info;
+use crate::management::{self, MANAGEMENT_CONTENT_TYPE};
+use crate::policy::{Verdict, WritePolicy};
+use crate::ratelimit::{RateLimited, RateLimiter, ReqSource};
+use crate::replay::ReplayGuard;
+use crate::shutdown::Shutdown;
+use crate::sweeper::Sweeper;
+
+/// How often idle threads check whether the relay should stop
+const TICK: Duration = Duration::from_millis(250);
+
+/// State shared by the worker threads.
+///
+/// The db sits behind a read-write lock so queries run side by side, and
+/// the expensive work (decryption, signature checks, attestation quotes)
+/// happens before a write lock is taken.
+pub struct Relay {
+    db: RwLock<DataHolder>,
+    pub attestor: Attestor,
+    /// Replaced as a whole on reload, so requests keep a consistent snapshot
+    config: RwLock<Arc<RelayConfig>>,
+    admin: Mutex<Admin>,
+    limiter: Mutex<RateLimiter>,
+    auth: Mutex<Authenticator>,
+    replay: Mutex<ReplayGuard>,
+    /// Held through a save or reload, so they don't store over each other
+    saving: Mutex<()>,
+    /// Asked in order about every new event, until one doesn't accept it
+    write_policies: Vec<Box<dyn WritePolicy>>,
+    pub shutdown: Shutdown,
+}
+
+/// Counters reported by the admin API
+#[derive(Serialize, Debug)]
+pub struct RelayStats {
+    #[serde(flatten)]
+    pub db: Stats,
+    pub rate_limited: RateLimited,
+}
+
+impl Relay {
+    pub fn new(db: DataHolder, attestor: Attestor, config: RelayConfig) -> Self {
+        Self {
+            db: RwLock::new(db),
+            attestor,
+            admin: Mutex::new(Admin::new(admin::built_in_pubkeys(), &config.admin)),
+            auth: Mutex::new(Authenticator::new(&config.network.url)),
+            config: RwLock::new(Arc::new(config)),
+            limiter: Mutex::new(RateLimiter::default()),
+            replay: Mutex::new(ReplayGuard::default()),
+            saving: Mutex::new(()),
+            write_policies: Vec::new(),
+            shutdown: Shutdown::default(),
+        }
+    }
+
+    /// Also ask `policy` about every new event
+    pub fn with_write_policy(mut self, policy: impl WritePolicy + 'static) -> Self {
+        self.write_policies.push(Box::new(policy));
+        self
+    }
+
+    /// What the write policies decided about `event`, sent by `peer`
+    pub fn check_write(&self, event: &Event, peer: Option<SocketAddr>) -> Verdict {
+        self.write_policies
+            .iter()
+            .map(|policy| policy.check(event, peer))
+            .find(|verdict| *verdict != Verdict::Accept)
+            .unwrap_or(Verdict::Accept)
+    }
+
+    /// Whether any write policy sends events out of the enclave
+    pub fn sends_events_out(&self) -> bool {
+        self.write_policies
+            .iter()
+            .any(|policy| policy.leaves_enclave())
+    }
+
+    // A lock is only poisoned if a thread panicked while holding it. Every
+    // change to the db leaves it consistent, so the others keep going.
+
+    pub fn db(&self) -> RwLockReadGuard<'_, DataHolder> {
+        self.db.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn db_mut(&self) -> RwLockWriteGuard<'_, DataHolder> {
+        self.db.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Seal the db's changes and send them to the filerunner. The db is
+    /// only locked while sealing, not while the filerunner is waited on.
+    pub fn save(&self) -> Result<()> {
+        let _saving = self.pause_saves();
+        let Some(pending) = self.db_mut().seal_changes()? else {
+            return Ok(());
+        };
+        let stored = pending.store_to_filerunner();
+        self.db_mut().finish_save(pending, stored.is_ok());
+        stored
+    }
+
+    /// Wait for a running save, and keep others from starting until dropped
+    pub fn pause_saves(&self) -> MutexGuard<'_, ()> {
+        self.saving.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn config(&self) -> Arc<RelayConfig> {
+        self.config
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Use a new config for the following requests
+    pub fn set_config(&self, config: RelayConfig) {
+        self.admin().set_config(&config.admin);
+        *self.config.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(config);
+    }
+
+    pub fn admin(&self) -> MutexGuard<'_, Admin> {
+        self.admin.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn limiter(&self) -> MutexGuard<'_, RateLimiter> {
+        self.limiter.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn auth(&self) -> MutexGuard<'_, Authenticator> {
+        self.auth.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn replay(&self) -> MutexGuard<'_, ReplayGuard> {
+        self.replay.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn stats(&self) -> RelayStats {
+        RelayStats {
+            db: self.db().stats(),
+            rate_limited: self.limiter().counters(),
+        }
+    }
+}
+
+/// Handle a request and return a response.
+fn nostrust_response(req: &mut Request, relay: &Relay) -> Response<Cursor<Vec<u8>>> {
+    let config = relay.config();
+
+    // blocked and rate limited before anything else, admin requests included
+    let peer = req.remote_addr().copied();
+    if let Some(addr) = peer {
+        if relay.db().moderation.is_ip_blocked(&addr.ip().to_string()) {
+            println!("Refused request from blocked ip {}", addr.ip());
+            return Response::from_string("blocked").with_status_code(403);
+        }
+        let allowed =
+            relay
+                .limiter()
+                .allow_connection(addr.ip(), &config.rate_limits, Instant::now());
+        if !allowed {
+            return reply(429, notice("rate-limited: too many requests".to_string()));
+        }
+    }
+
+    if info::is_info_request(req) {
+        return info::handle(req, relay, &config);
+    }
+
+    let body = match read_body(req, config.limits.max_message_length) {
+        Ok(body) => body,
+        Err(response) => return response,
+    };
+
+    let path = req.url().to_string();
+    let action = relay.admin().action(&path).map(str::to_string);
+    if let Some(action) = action {
+        return admin::handle(req, &body, &action, relay);
+    }
+
+    // NIP-86 management requests share the relay's URL
+    let is_management = req.headers().iter().any(|header| {
+        header.field.equiv("Content-Type") && header.value.as_str() == MANAGEMENT_CONTENT_TYPE
+    });
+    if is_management {
+        return management::handle(req, &body, relay);
+    }
+
+    let (status, body) = handle_body(relay, &body, peer, &config);
+    reply(status, body)
+}
+
+/// Read the body of `req`, without buffering more than `max_length` bytes
+fn read_body(req: &mut Request, max_length: usize) -> Result<Vec<u8>, Response<Cursor<Vec<u8>>>> {
+    let too_long = || {
+        let message = format!("invalid: message is longer than {} bytes", max_length);
+        reply(413, notice(message))
+    };
+    // refuse bodies that announce they are too large without reading them
+    if req.body_length().is_some_and(|length| length > max_length) {
+        return Err(too_long());
+    }
+
+    let mut body = Vec::new();
+    if let Err(e) = req
+        .as_reader()
+        .take(max_length as u64 + 1)
+        .read_to_end(&mut body)
+    {
+        return Err(reply(
+            400,
+            notice(format!("invalid: could not read body: {}", e)),
+        ));
+    }
+    if body.len() > max_length {
+        return Err(too_long());
+    }
+    Ok(body)
+}
+
+fn reply(status: u16, body: Vec<u8>) -> Response<Cursor<Vec<u8>>> {
+    Response::from_data(body).with_status_code(status)
+}
+
+fn notice(message: String) -> Vec<u8> {
+    RelayMessage::Notice(message).to_body()
+}
+
+/// Handle a request body and return the status code and response body.
+/// Every malformed request gets a NOTICE instead of taking the relay down.
+/// `peer` is the connection the request came in on.
+fn handle_body(
+    relay: &Relay,
+    body: &[u8],
+    peer: Option<SocketAddr>,
+    config: &RelayConfig,
+) -> (u16, Vec<u8>) {
+    let message: ClientMessage = match serde_json::from_slice(body) {
+        Ok(message) => message,
+        Err(e) => return (400, notice(format!("invalid: {}", e))),
+    };
+
+    match message {
+        // the info request sets up the encrypted channel, so it is the
+        // only message allowed in plaintext
+        ClientMessage::Info(_) => {
+            println!("Received: {:#?}", message);
+            response_body(relay, message, peer, None, config)
+        }
+        ClientMessage::Encrypted(encrypted) => {
+            let session = match relay.attestor.session(&encrypted.pubkey) {
+                Ok(session) => session,
+                Err(e) => return (400, notice(format!("invalid: session key: {}", e))),
+            };
+            // only requests that decrypt can move a session's counter on
+            let message = session.decrypt_request(&encrypted).and_then(|plaintext| {
+                relay
+                    .replay()
+                    .check(&encrypted.pubkey, encrypted.counter, counter_now())?;
+                Ok(serde_json::from_slice::<ClientMessage>(&plaintext)?)
+            });
+            // errors from here on are encrypted like any other response
+            let (status, body) = match message {
+                Ok(ClientMessage::Encrypted(_)) => {
+                    (400, notice("invalid: nested encrypted message".to_string()))
+                }
+                Ok(message) => {
+                    // the contents stay inside the enclave, stdout goes to the host
+                    println!("Received encrypted message");
+                    response_body(relay, message, peer, Some(&encrypted.pubkey), config)
+                }
+                Err(e) => (400, notice(format!("invalid: encrypted message: {}", e))),
+            };
+            match session.encrypt_response(&encrypted, &body) {
+                Ok(payload) => (status, payload.into_bytes()),
+                Err(e) => (
+                    500,
+                    notice(format!("error: could not encrypt response: {}", e)),
+                ),
+            }
+        }
+        _ => (
+            400,
+            notice("invalid: requests must be encrypted for the enclave".to_string()),
+        ),
+    }
+}
+
+/// Why a session authenticated as `authed` may not act for `user`, if it may not (NIP-42)
+fn unauthorized(authed: Option<&str>, user: &str) -> Option<String> {
+    match authed {
+        None => Some("auth-required: authenticate as this user first".to_string()),
+        Some(pubkey) if pubkey != user => {
+            Some("restricted: authenticated as another user".to_string())
+        }
+        Some(_) => None,
+    }
+}
+
+/// Handle a decrypted message and return the status code and response body.
+/// `session` is the encrypted session it came in, if any.
+fn response_body(
+    relay: &Relay,
+    message: ClientMessage,
+    peer: Option<SocketAddr>,
+    session: Option<&str>,
+    config: &RelayConfig,
+) -> (u16, Vec<u8>) {
+    let authed = session.and_then(|session| {
+        relay
+            .auth()
+            .pubkey(session, Instant::now())
+            .map(str::to_string)
+    });
+
+    match &message {
+        ClientMessage::Challenge | ClientMessage::Auth(_) if session.is_none() => {
+            return (
+                400,
+                notice("invalid: AUTH needs an encrypted session".to_string()),
+            );
+        }
+        ClientMessage::Challenge => {
+            let challenge = relay.auth().challenge(session.unwrap(), Instant::now());
+            return (200, RelayMessage::Auth(challenge).to_body());
+        }
+        ClientMessage::Auth(event) => {
+            let now = chrono::Utc::now().timestamp() as usize;
+            let result = if event.verify() {
+                relay
+                    .auth()
+                    .authenticate(session.unwrap(), event, now, Instant::now())
+            } else {
+                Err(anyhow!("auth event failed verification"))
+            };
+            let reply = match result {
+                Ok(()) => RelayMessage::Ok(event.id.clone(), true, String::new()),
+                Err(e) => RelayMessage::rejected(&event.id, "invalid", &e.to_string()),
+            };
+            return (200, reply.to_body());
+        }
+        ClientMessage::Event(event) => {
+            let now = chrono::Utc::now().timestamp() as usize;
+            if let Some(reason) = db::check_event(event, &config.limits, now) {
+                println!("Event rejected: {}", reason);
+                let reply = RelayMessage::rejected(&event.id, "invalid", &reason);
+                return (200, reply.to_body());
+            }
+            if event.kind == AUTH_KIND {
+                let reply =
+                    RelayMessage::rejected(&event.id, "invalid", "send auth events with AUTH");
+                return (200, reply.to_body());
+            }
+            // only the author may publish a protected event (NIP-70)
+            if event.is_protected() {
+                if let Some(reason) = unauthorized(authed.as_deref(), &event.pubkey) {
+                    let reply = RelayMessage::Ok(event.id.clone(), false, reason);
+                    return (200, reply.to_body());
+                }
+            }
+            if let Some(reason) = db::check_pow(event, &config.limits) {
+                let reply = RelayMessage::rejected(&event.id, "pow", &reason);
+                return (200, reply.to_body());
+            }
+            // the signature is checked, so the pubkey is really the sender's
+            let allowed = relay
+                .limiter()
+                .allow_event(event, &config.rate_limits, Instant::now());
+            if !allowed {
+                let reply = RelayMessage::rejected(&event.id, "rate-limited", "slow down");
+                return (200, reply.to_body());
+            }
+            // erasures are always honoured, whatever the policies say
+            if !matches!(event.kind, 5 | 62) {
+                match relay.check_write(event, peer) {
+                    Verdict::Accept => {}
+                    Verdict::Reject(message) => {
+                        println!("Event rejected by write policy: {}", message);
+                        let reply = RelayMessage::Ok(event.id.clone(), false, message);
+                        return (200, reply.to_body());
+                    }
+                    Verdict::ShadowReject(reason) => {
+                        println!("Event shadow-rejected by write policy: {}", reason);
+                        let reply = RelayMessage::Ok(event.id.clone(), true, String::new());
+                        return (200, reply.to_body());
+                    }
+                }
+            }
+        }
+        ClientMessage::Req(id, filters) => {
+            if let Some(reason) = db::check_req(filters, &config.limits) {
+                let reply = RelayMessage::Closed(id.clone(), format!("invalid: {}", reason));
+                return (200, reply.to_body());
+            }
+            let source = match &authed {
+                Some(pubkey) => Some(ReqSource::User(pubkey.clone())),
+                None => peer.map(|peer| ReqSource::Ip(peer.ip())),
+            };
+            let allowed = source.is_none_or(|source| {
+                relay
+                    .limiter()
+                    .allow_req(source, &config.rate_limits, Instant::now())
+            });
+            if !allowed {
+                let reply = RelayMessage::Closed(id.clone(), "rate-limited: slow down".to_string());
+                return (200, reply.to_body());
+            }
+            // only the user may change whom they follow
+            if let Some(reason) = unauthorized(authed.as_deref(), id) {
+                return (200, RelayMessage::Closed(id.clone(), reason).to_body());
+            }
+        }
+        ClientMessage::Close(user, _) => {
+            if let Some(reason) = unauthorized(authed.as_deref(), user) {
+                return (200, RelayMessage::Closed(user.clone(), reason).to_body());
+            }
+        }
+        // a feed shows whom the user follows
+        ClientMessage::Get(user) => {
+            if let Some(reason) = unauthorized(authed.as_deref(), user) {
+                return (401, notice(reason));
+            }
+        }
+        // the export holds everything stored for the user
+        ClientMessage::Export(user) => {
+            if let Some(reason) = unauthorized(authed.as_deref(), user) {
+                return (401, notice(reason));
+            }
+        }
+        ClientMessage::Receipt(_) if authed.is_none() => {
+            return (401, notice(unauthorized(None, "").unwrap()));
+        }
+        _ => {}
+    }
+
+    let receipt_query = matches!(message, ClientMessage::Receipt(_));
+    let erasure = match &message {
+        ClientMessage::Event(event) if matches!(event.kind, 5 | 62) => Some(event.id.clone()),
+        _ => None,
+    };
+    let reply = if db::is_query(&message) {
+        relay.db().handle_query(message, &relay.attestor, config)
+    } else {
+        relay
+            .db_mut()
+            .handle_message(message, &relay.attestor, config)
+    };
+    // a receipt only names a sealed state version once a save stored it
+    let new_erasure = erasure.filter(|_| {
+        matches!(&reply, Some(RelayMessage::Ok(_, true, message)) if message == db::ERASURE_NOT_SAVED)
+    });
+    let reply = match new_erasure {
+        Some(id) => match relay.save() {
+            Ok(()) => relay
+                .db()
+                .handle_query(ClientMessage::Receipt(id), &relay.attestor, config),
+            Err(e) => {
+                println!("Failed to save erasure: {}", e);
+                reply
+            }
+        },
+        None => reply,
+    };
+    match reply {
+        // receipts name the user and their erased events
+        Some(RelayMessage::Receipt(receipt))
+            if receipt_query && authed.as_deref() != Some(&receipt.pubkey) =>
+        {
+            (
+                401,
+                notice(unauthorized(authed.as_deref(), &receipt.pubkey).unwrap()),
+            )
+        }
+        Some(message @ RelayMessage::Notice(_)) => (400, message.to_body()),
+        Some(message) => (200, message.to_body()),
+        None => (200, b"OK".to_vec()),
+    }
+}
+
+fn answer(mut request: Request, relay: &Relay) {
+    let resp = nostrust_response(&mut request, relay);
+    if let Err(e) = request.respond(resp) {
+        println!("Failed to answer request: {}", e);
+    }
+}
+
+/// Answer requests until a shutdown is requested
+fn work(server: &Server, relay: &Relay) {
+    while !relay.shutdown.is_requested() {
+        match server.recv_timeout(TICK) {
+            Ok(Some(request)) => answer(request, relay),
+            Ok(None) => {}
+            Err(e) => println!("Failed to receive request: {}", e),
+        }
+    }
+}
+
+/// Answer requests on `workers` threads while this thread sweeps and saves
+/// the db on schedule, until a shutdown is requested. Returns once every
+/// request that had already arrived is answered.
+pub fn serve(
+    server: &Server,
+    relay: &Relay,
+    workers: usize,
+    autosave: &mut Autosave,
+    sweeper: &mut Sweeper,
+) {
+    thread::scope(|scope| {
+        for _ in 0..workers.max(1) {
+            scope.spawn(|| work(server, relay));
+        }
+
+        while !relay.shutdown.is_requested() {
+            thread::sleep(TICK);
+            if sweeper.is_due(Instant::now()) {
+                sweeper.run(&mut relay.db_mut(), &relay.config().retention);
+            }
+            autosave.run(relay);
+            relay
+                .limiter()
+                .prune(&relay.config().rate_limits, Instant::now());
+            relay.auth().prune(Instant::now());
+            relay.replay().prune(counter_now());
+            relay.shutdown.poll_host();
+        }
+    });
+
+    println!("Draining pending requests...");
+    while let Ok(Some(request)) = server.try_recv() {
+        answer(request, relay);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Rate, RateLimitsConfig};
+    use crate::policy::PluginPolicy;
+    use core::channel::Session;
+    use core::event::Event;
+    use core::filter::Filter;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use secp256k1::{PublicKey, Secp256k1, SecretKey};
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::io::Write;
+    use std::net::{SocketAddr, TcpStream};
+    use std::rc::Rc;
+    use std::time::Instant;
+
+    const UNLIMITED: Rate = Rate {
+        per_minute: 0,
+        burst: 0,
+    };
+
+    /// An encrypted request posting a fresh signed event
+    fn post_request(relay_pubkey: &PublicKey, seed: usize) -> Vec<u8> {
+        let secp = Secp256k1::new();
+        let mut bytes = [1u8; 32];
+        bytes[..8].copy_from_slice(&(seed as u64 + 1).to_be_bytes());
+        let privkey = SecretKey::from_slice(&bytes).unwrap();
+        let pubkey = PublicKey::from_secret_key(&secp, &privkey);
+
+        let event = Event::new(
+            hex::encode(privkey.secret_bytes()),
+            hex::encode(pubkey.serialize()),
+            1,
+            vec![],
+            format!("post {}", seed),
+        );
+        let plaintext = serde_json::to_vec(&ClientMessage::Event(event)).unwrap();
+        let encrypted = Session::client(&privkey, relay_pubkey)
+            .encrypt_request(&plaintext)
+            .unwrap();
+        serde_json::to_vec(&ClientMessage::Encrypted(encrypted)).unwrap()
+    }
+
+    /// POST `body` and return the status code
+    fn post(addr: SocketAddr, body: &[u8]) -> u16 {
+        post_to(addr, "/", body)
+    }
+
+    /// POST `body` to `path` and return the status code
+    fn post_to(addr: SocketAddr, path: &str, body: &[u8]) -> u16 {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let head = format!(
+            "POST {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n\r\n",
+            path,
+            body.len()
+        );
+        stream.write_all(head.as_bytes()).unwrap();
+        stream.write_all(body).unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response[9..12].parse().unwrap()
+    }
+
+    /// Serve `requests` from `clients` threads with `workers` worker threads.
+    /// Returns the relay and the requests per second it answered.
+    fn run(workers: usize, clients: usize, requests: usize) -> (Relay, f64) {
+        let config = RelayConfig {
+            rate_limits: RateLimitsConfig {
+                events: UNLIMITED,
+                kinds: vec![],
+                reqs_per_user: UNLIMITED,
+                connections_per_ip: UNLIMITED,
+            },
+            ..RelayConfig::default()
+        };
+        let relay = Relay::new(DataHolder::default(), Attestor::new(), config);
+        let relay_pubkey = relay.attestor.transport_pubkey();
+        let bodies: Vec<Vec<u8>> = (0..requests)
+            .map(|seed| post_request(&relay_pubkey, seed))
+            .collect();
+
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+
+        let start = Instant::now();
+        thread::scope(|scope| {
+            for _ in 0..workers {
+                scope.spawn(|| work(&server, &relay));
+            }
+            let senders: Vec<_> = bodies
+                .chunks(requests.div_ceil(clients))
+                .map(|chunk| {
+                    scope.spawn(move || {
+                        for body in chunk {
+                            assert_eq!(post(addr, body), 200);
+                        }
+                    })
+                })
+                .collect();
+            for sender in senders {
+                sender.join().unwrap();
+            }
+            relay.shutdown.request();
+        });
+        let throughput = requests as f64 / start.elapsed().as_secs_f64();
+
+        (relay, throughput)
+    }
+
+    #[test]
+    fn test_concurrent_posts_are_all_stored() {
+        let (relay, _) = run(4, 8, 64);
+
+        assert_eq!(relay.db().stats().events, 64);
+        assert_eq!(relay.db().stats().users, 64);
+    }
+
+    fn test_relay() -> Relay {
+        Relay::new(
+            DataHolder::default(),
+            Attestor::new(),
+            RelayConfig::default(),
+        )
+    }
+
+    /// Handle `body` and check the relay answered with a NOTICE
+    fn assert_notice(relay: &Relay, body: &[u8]) -> u16 {
+        let (status, reply) = handle_body(relay, body, None, &relay.config());
+        match RelayMessage::from_body(&reply) {
+            Ok(RelayMessage::Notice(_)) => {}
+            other => panic!("expected a NOTICE for {:?}, got {:?}", body, other),
+        }
+        status
+    }
+
+    thread_local! {
+        /// Client session of the test on this thread with each relay, so its counters keep rising
+        static SESSIONS: RefCell<HashMap<PublicKey, Rc<Session>>> = RefCell::default();
+    }
+
+    /// Send `plaintext` encrypted from `peer`, and return the status and the decrypted reply
+    fn send_encrypted(relay: &Relay, plaintext: &[u8], peer: Option<SocketAddr>) -> (u16, Vec<u8>) {
+        let relay_pubkey = relay.attestor.transport_pubkey();
+        let session = SESSIONS.with_borrow_mut(|sessions| {
+            let privkey = SecretKey::from_slice(&[7u8; 32]).unwrap();
+            let session = sessions
+                .entry(relay_pubkey)
+                .or_insert_with(|| Rc::new(Session::client(&privkey, &relay_pubkey)));
+            session.clone()
+        });
+        let encrypted = session.encrypt_request(plaintext).unwrap();
+        let body = serde_json::to_vec(&ClientMessage::Encrypted(encrypted.clone())).unwrap();
+        let (status, reply) = handle_body(relay, &body, peer, &relay.config());
+        let reply = session
+            .decrypt_response(&encrypted, &String::from_utf8(reply).unwrap())
+            .unwrap();
+        (status, reply)
+    }
+
+    /// Authenticate the session `send_encrypted` uses as `user`
+    fn authenticate(relay: &Relay, user: &str) {
+        let privkey = SecretKey::from_slice(&[7u8; 32]).unwrap();
+        let session =
+            hex::encode(PublicKey::from_secret_key(&Secp256k1::new(), &privkey).serialize());
+        let challenge = relay.auth().challenge(&session, Instant::now());
+        let now = chrono::Utc::now().timestamp() as usize;
+        let event = Event {
+            id: "id".to_string(),
+            pubkey: user.to_string(),
+            created_at: now,
+            kind: AUTH_KIND,
+            tags: vec![
+                vec!["relay".to_string(), relay.config().network.url.clone()],
+                vec!["challenge".to_string(), challenge],
+            ],
+            content: String::new(),
+            sig: "sig".to_string(),
+        };
+        relay
+            .auth()
+            .authenticate(&session, &event, now, Instant::now())
+            .unwrap();
+    }
+
+    #[test]
+    fn test_malformed_json_gets_notice() {
+        let relay = test_relay();
+        let bodies: &[&[u8]] = &[
+            b"",
+            b"{",
+            b"null",
+            b"[]",
+            b"\xff\xfe",
+            br#"{"Event":"#,
+            br#"{"Unknown":"x"}"#,
+            br#"{"Event":{"id":1}}"#,
+            br#"{"Req":["a"]}"#,
+            br#"{"Encrypted":{"pubkey":"zz","payload":"!!"}}"#,
+            br#"["EVENT",{}]"#,
+        ];
+        for body in bodies {
+            assert_eq!(assert_notice(&relay, body), 400);
+        }
+
+        // deep nesting must not overflow the stack
+        let nested = "[".repeat(100_000);
+        assert_eq!(assert_notice(&relay, nested.as_bytes()), 400);
+    }
+
+    #[test]
+    fn test_random_mutations_never_panic() {
+        let relay = test_relay();
+        let valid = post_request(&relay.attestor.transport_pubkey(), 0);
+        let mut rng = StdRng::seed_from_u64(38);
+
+        for _ in 0..500 {
+            let mut body = valid.clone();
+            for _ in 0..rng.gen_range(1..8) {
+                let i = rng.gen_range(0..body.len());
+                body[i] = rng.gen();
+            }
+            let cut = rng.gen_range(0..=body.len());
+            body.truncate(cut);
+            handle_body(&relay, &body, None, &relay.config());
+        }
+    }
+
+    #[test]
+    fn test_bad_encrypted_messages_get_notice() {
+        let relay = test_relay();
+        for plaintext in [&b"not json"[..], br#"{"Unknown":1}"#] {
+            let (status, reply) = send_encrypted(&relay, plaintext, None);
+            assert_eq!(status, 400);
+            assert!(matches!(
+                RelayMessage::from_body(&reply),
+                Ok(RelayMessage::Notice(_))
+            ));
+        }
+
+        // a REQ without an author is a NOTICE, not a panic
+        authenticate(&relay, "me");
+        let (status, reply) = send_encrypted(&relay, br#"{"Req":["me",[]]}"#, None);
+        assert_eq!(status, 400);
+        assert!(matches!(
+            RelayMessage::from_body(&reply),
+            Ok(RelayMessage::Notice(message)) if message.starts_with("invalid:")
+        ));
+    }
+
+    #[test]
+    fn test_replayed_request_is_refused() {
+        let relay = test_relay();
+        let body = post_request(&relay.attestor.transport_pubkey(), 0);
+
+        assert_eq!(handle_body(&relay, &body, None, &relay.config()).0, 200);
+        assert_eq!(handle_body(&relay, &body, None, &relay.config()).0, 400);
+        assert_eq!(relay.db().stats().events, 1);
+    }
+
+    #[test]
+    fn test_auth_binds_session_to_pubkey() {
+        let relay = test_relay();
+        let privkey = SecretKey::from_slice(&[3u8; 32]).unwrap();
+        let user = hex::encode(PublicKey::from_secret_key(&Secp256k1::new(), &privkey).serialize());
+        let send = |message: ClientMessage| {
+            let plaintext = serde_json::to_vec(&message).unwrap();
+            let (status, reply) = send_encrypted(&relay, &plaintext, None);
+            (status, RelayMessage::from_body(&reply).ok())
+        };
+        let auth = |challenge: &str| {
+            Event::new(
+                hex::encode(privkey.secret_bytes()),
+                user.clone(),
+                AUTH_KIND,
+                vec![
+                    vec!["relay".to_string(), "http://localhost:8080".to_string()],
+                    vec!["challenge".to_string(), challenge.to_string()],
+                ],
+                String::new(),
+            )
+        };
+
+        let (status, reply) = send(ClientMessage::Get(user.clone()));
+        assert_eq!(status, 401);
+        assert!(matches!(reply, Some(RelayMessage::Notice(m)) if m.starts_with("auth-required:")));
+
+        let Some(RelayMessage::Auth(challenge)) = send(ClientMessage::Challenge).1 else {
+            panic!("expected a challenge");
+        };
+        let (_, reply) = send(ClientMessage::Auth(auth("wrong")));
+        assert!(matches!(reply, Some(RelayMessage::Ok(_, false, _))));
+        let (_, reply) = send(ClientMessage::Auth(auth(&challenge)));
+        assert!(matches!(reply, Some(RelayMessage::Ok(_, true, _))));
+
+        // the session now acts for the user, and only for them
+        assert_eq!(send(ClientMessage::Get(user.clone())).0, 200);
+        let (status, reply) = send(ClientMessage::Get("02".repeat(33)));
+        assert_eq!(status, 401);
+        assert!(matches!(reply, Some(RelayMessage::Notice(m)) if m.starts_with("restricted:")));
+
+        // auth events are never stored as posts
+        let (_, reply) = send(ClientMessage::Event(auth(&challenge)));
+        assert!(matches!(reply, Some(RelayMessage::Ok(_, false, _))));
+
+        // authentication needs an encrypted session
+        let body = serde_json::to_vec(&ClientMessage::Challenge).unwrap();
+        assert_eq!(assert_notice(&relay, &body), 400);
+    }
+
+    #[test]
+    fn test_protected_events_need_their_author() {
+        let relay = test_relay();
+        let privkey = SecretKey::from_slice(&[3u8; 32]).unwrap();
+        let author =
+            hex::encode(PublicKey::from_secret_key(&Secp256k1::new(), &privkey).serialize());
+        let event = Event::new(
+            hex::encode(privkey.secret_bytes()),
+            author.clone(),
+            1,
+            vec![vec!["-".to_string()]],
+            "only from me".to_string(),
+        );
+        let send = || {
+            let plaintext = serde_json::to_vec(&ClientMessage::Event(event.clone())).unwrap();
+            let (_, reply) = send_encrypted(&relay, &plaintext, None);
+            match RelayMessage::from_body(&reply) {
+                Ok(RelayMessage::Ok(_, accepted, message)) => (accepted, message),
+                other => panic!("expected OK, got {:?}", other),
+            }
+        };
+
+        let (accepted, message) = send();
+        assert!(!accepted && message.starts_with("auth-required:"));
+        // a third party re-broadcasting the event
+        authenticate(&relay, &"02".repeat(33));
+        let (accepted, message) = send();
+        assert!(!accepted && message.starts_with("restricted:"));
+        assert_eq!(relay.db().stats().events, 0);
+
+        authenticate(&relay, &author);
+        assert!(send().0);
+        assert_eq!(relay.db().stats().events, 1);
+    }
+
+    /// Turns away posts by their content
+    struct ContentPolicy;
+
+    impl WritePolicy for ContentPolicy {
+        fn check(&self, event: &Event, _: Option<SocketAddr>) -> Verdict {
+            match event.content.as_str() {
+                "buy now" => Verdict::Reject("blocked: no ads".to_string()),
+                "spam" => Verdict::ShadowReject("looks like spam".to_string()),
+                _ => Verdict::Accept,
+            }
+        }
+    }
+
+    #[test]
+    fn test_write_policies() {
+        let relay = test_relay().with_write_policy(ContentPolicy);
+        let config = relay.config();
+        let privkey = SecretKey::from_slice(&[3u8; 32]).unwrap();
+        let pubkey =
+            hex::encode(PublicKey::from_secret_key(&Secp256k1::new(), &privkey).serialize());
+        let send = |kind: usize, content: &str| {
+            let event = Event::new(
+                hex::encode(privkey.secret_bytes()),
+                pubkey.clone(),
+                kind,
+                vec![],
+                content.to_string(),
+            );
+            let (_, reply) =
+                response_body(&relay, ClientMessage::Event(event), None, None, &config);
+            RelayMessage::from_body(&reply).unwrap()
+        };
+
+        assert!(matches!(send(1, "hello"), RelayMessage::Ok(_, true, _)));
+        assert_eq!(relay.db().stats().events, 1);
+
+        match send(1, "buy now") {
+            RelayMessage::Ok(_, false, message) => assert_eq!(message, "blocked: no ads"),
+            other => panic!("expected OK false, got {:?}", other),
+        }
+        // looks accepted to the sender, but isn't stored
+        assert!(matches!(send(1, "spam"), RelayMessage::Ok(_, true, _)));
+        assert_eq!(relay.db().stats().events, 1);
+
+        // deletions are never turned away. There is no filerunner to save
+        // them to, so they are answered without a receipt.
+        assert!(matches!(
+            send(5, "spam"),
+            RelayMessage::Ok(_, true, message) if message == db::ERASURE_NOT_SAVED
+        ));
+        assert_eq!(relay.db().stats().events, 0);
+
+        // only the plugin is advertised as sending events out of the enclave
+        assert!(!relay.sends_events_out());
+        assert!(test_relay()
+            .with_write_policy(PluginPolicy)
+            .sends_events_out());
+    }
+
+    #[test]
+    fn test_rejected_event_gets_ok_false() {
+        let relay = test_relay();
+        let secp = Secp256k1::new();
+        let privkey = SecretKey::from_slice(&[3u8; 32]).unwrap();
+        let pubkey = PublicKey::from_secret_key(&secp, &privkey);
+        let mut event = Event::new(
+            hex::encode(privkey.secret_bytes()),
+            hex::encode(pubkey.serialize()),
+            1,
+            vec![],
+            "x".repeat(RelayConfig::default().limits.max_content_length + 1),
+        );
+        let plaintext = serde_json::to_vec(&ClientMessage::Event(event.clone())).unwrap();
+        let (status, reply) = send_encrypted(&relay, &plaintext, None);
+        assert_eq!(status, 200);
+        match RelayMessage::from_body(&reply).unwrap() {
+            RelayMessage::Ok(id, false, reason) => {
+                assert_eq!(id, event.id);
+                assert!(reason.starts_with("invalid:"));
+            }
+            other => panic!("expected OK false, got {:?}", other),
+        }
+
+        // a bad signature is rejected the same way
+        event.content = "tampered".to_string();
+        let plaintext = serde_json::to_vec(&ClientMessage::Event(event)).unwrap();
+        let (_, reply) = send_encrypted(&relay, &plaintext, None);
+        assert!(matches!(
+            RelayMessage::from_body(&reply),
+            Ok(RelayMessage::Ok(_, false, _))
+        ));
+        assert_eq!(relay.db().stats().events, 0);
+    }
+
+    #[test]
+    fn test_resent_event_with_another_id_is_refused() {
+        let relay = test_relay();
+        let config = relay.config();
+        let privkey = SecretKey::from_slice(&[3u8; 32]).unwrap();
+        let pubkey =
+            hex::encode(PublicKey::from_secret_key(&Secp256k1::new(), &privkey).serialize());
+        let send = |event: &Event| {
+            let message = ClientMessage::Event(event.clone());
+            let (_, reply) = response_body(&relay, message, None, None, &config);
+            RelayMessage::from_body(&reply).unwrap()
+        };
+        // same signed contents, another id
+        let renamed = |event: &Event| Event {
+            id: "1".repeat(64),
+            ..event.clone()
+        };
+
+        let post = Event::new(
+            hex::encode(privkey.secret_bytes()),
+            pubkey.clone(),
+            1,
+            vec![],
+            "once".to_string(),
+        );
+        assert!(matches!(send(&post), RelayMessage::Ok(_, true, _)));
+        match send(&renamed(&post)) {
+            RelayMessage::Ok(_, false, message) => assert!(message.starts_with("invalid:")),
+            other => panic!("expected OK false, got {:?}", other),
+        }
+        assert_eq!(relay.db().stats().events, 1);
+
+        // an erasure can't be executed twice under another id either
+        let deletion = Event::new(
+            hex::encode(privkey.secret_bytes()),
+            pubkey,
+            5,
+            vec![],
+            String::new(),
+        );
+        assert!(matches!(send(&deletion), RelayMessage::Ok(_, true, _)));
+        assert!(matches!(
+            send(&renamed(&deletion)),
+            RelayMessage::Ok(_, false, _)
+        ));
+        assert_eq!(relay.db().audit.records.len(), 1);
+    }
+
+    #[test]
+    fn test_huge_body_is_refused() {
+        let relay = test_relay();
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+        let huge = vec![b'['; RelayConfig::default().limits.max_message_length + 1];
+
+        thread::scope(|scope| {
+            scope.spawn(|| work(&server, &relay));
+            assert_eq!(post(addr, &huge), 413);
+            assert_eq!(post(addr, b"{garbage"), 400);
+            // admin requests are read under the same limit, before any auth
+            assert_eq!(post_to(addr, "/admin/stats", &huge), 413);
+            assert_eq!(post_to(addr, "/admin/stats", b"{}"), 401);
+            relay.shutdown.request();
+        });
+    }
+
+    #[test]
+    fn test_rate_limits() {
+        let slow = Rate {
+            per_minute: 1,
+            burst: 2,
+        };
+        let config = RelayConfig {
+            rate_limits: RateLimitsConfig {
+                events: slow,
+                reqs_per_user: slow,
+                ..RateLimitsConfig::default()
+            },
+            ..RelayConfig::default()
+        };
+        let relay = Relay::new(DataHolder::default(), Attestor::new(), config);
+        let privkey = SecretKey::from_slice(&[3u8; 32]).unwrap();
+        let pubkey = PublicKey::from_secret_key(&Secp256k1::new(), &privkey);
+
+        let send = |message: ClientMessage, peer: &str| {
+            let plaintext = serde_json::to_vec(&message).unwrap();
+            let (_, reply) = send_encrypted(&relay, &plaintext, peer.parse().ok());
+            // accepted REQs are answered with a plain "OK"
+            RelayMessage::from_body(&reply).ok()
+        };
+
+        let accepted: Vec<bool> = (0..3)
+            .map(|i| {
+                let event = Event::new(
+                    hex::encode(privkey.secret_bytes()),
+                    hex::encode(pubkey.serialize()),
+                    1,
+                    vec![],
+                    format!("post {}", i),
+                );
+                match send(ClientMessage::Event(event), "10.0.0.1:1000") {
+                    Some(RelayMessage::Ok(_, accepted, message)) => {
+                        assert!(accepted || message.starts_with("rate-limited:"));
+                        accepted
+                    }
+                    other => panic!("expected OK, got {:?}", other),
+                }
+            })
+            .collect();
+        assert_eq!(accepted, [true, true, false]);
+
+        // REQs are limited per user, whatever port they come from
+        authenticate(&relay, "me");
+        let req = || ClientMessage::Req("me".to_string(), vec![Filter::one_author("a".into())]);
+        for _ in 0..2 {
+            let plaintext = serde_json::to_vec(&req()).unwrap();
+            let peer = "10.0.0.1:1000".parse().ok();
+            assert_eq!(send_encrypted(&relay, &plaintext, peer).0, 200);
+        }
+        assert_eq!(
+            send(req(), "10.0.0.1:1000"),
+            Some(RelayMessage::Closed(
+                "me".to_string(),
+                "rate-limited: slow down".to_string()
+            ))
+        );
+        assert!(matches!(
+            send(req(), "10.0.0.1:2000"),
+            Some(RelayMessage::Closed(_, reason)) if reason.starts_with("rate-limited:")
+        ));
+        // other users have their own bucket
+        authenticate(&relay, "you");
+        let req = ClientMessage::Req("you".to_string(), vec![Filter::one_author("a".into())]);
+        assert_eq!(send(req, "10.0.0.1:2000"), None);
+
+        let stats = relay.stats();
+        assert_eq!((stats.rate_limited.events, stats.rate_limited.reqs), (1, 2));
+        assert_eq!(stats.db.events, 2);
+    }
+
+    #[test]
+    fn test_requests_per_ip_are_limited() {
+        let mut config = RelayConfig::default();
+        config.rate_limits.connections_per_ip = Rate {
+            per_minute: 1,
+            burst: 1,
+        };
+        let relay = Relay::new(DataHolder::default(), Attestor::new(), config);
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+
+        thread::scope(|scope| {
+            scope.spawn(|| work(&server, &relay));
+            assert_eq!(post(addr, b"{garbage"), 400);
+            assert_eq!(post(addr, b"{garbage"), 429);
+            assert_eq!(post_to(addr, "/admin/stats", b"{}"), 429);
+            relay.shutdown.request();
+        });
+        assert_eq!(relay.stats().rate_limited.connections, 2);
+    }
+
+    /// Load test: `cargo test --bin relay load_test -- --ignored --nocapture`.
+    /// Throughput should grow with the number of workers, up to the number of cores.
+    #[test]
+    #[ignore]
+    fn load_test() {
+        let cores = thread::available_parallelism().map_or(1, |n| n.get());
+        println!("{} cores available", cores);
+
+        let mut workers = 1;
+        while workers <= cores.max(4) {
+            let (_, throughput) = run(workers, 32, 2000);
+            println!("{:>2} workers: {:>8.0} requests/s", workers, throughput);
+            workers *= 2;
+        }
+    }
+}
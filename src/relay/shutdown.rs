@@ -0,0 +1,61 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::db::DataHolder;
+use crate::filerunner::{get_file, set_file};
+
+/// Tracks whether the relay should stop, shared by all worker threads.
+///
+/// Signals sent to the host never reach code inside the enclave, so the
+/// filerunner catches SIGTERM/SIGINT and raises a flag the relay polls for.
+/// Admins can also ask for a shutdown through the admin API.
+#[derive(Default)]
+pub struct Shutdown {
+    requested: AtomicBool,
+}
+
+impl Shutdown {
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// Ask the filerunner whether the host wants the relay to stop
+    pub fn poll_host(&self) {
+        if !self.is_requested() && get_file("/get-shutdown").is_ok() {
+            println!("Host asked the relay to shut down");
+            self.request();
+        }
+    }
+}
+
+/// Write the final seal and tell the filerunner whether it is safely stored.
+/// Returns the exit status of the relay.
+pub fn finish(db: &mut DataHolder) -> i32 {
+    println!("Shutting down and saving file...");
+    // expired events never make it into the final seal
+    db.purge_expired(chrono::Utc::now().timestamp() as usize);
+    let saved = match db.save_to_filerunner() {
+        Ok(()) => {
+            println!("Saved db");
+            true
+        }
+        Err(e) => {
+            println!("Failed to save db: {}", e);
+            false
+        }
+    };
+
+    let status = if saved { "saved" } else { "failed" };
+    if let Err(e) = set_file("/set-shutdown", status) {
+        println!("Failed to report shutdown to the filerunner: {}", e);
+    }
+
+    if saved {
+        0
+    } else {
+        1
+    }
+}
@@ -0,0 +1,60 @@
+use core::info::Retention;
+use std::time::{Duration, Instant};
+
+use crate::config::SweeperConfig;
+use crate::db::DataHolder;
+
+/// Purges expired events (NIP-40) and events outside the retention rules
+/// on an interval, so they don't linger in memory or make it into the
+/// next sealed snapshot. Every purge is recorded in the audit log.
+pub struct Sweeper {
+    interval: Option<Duration>,
+    last_sweep: Instant,
+}
+
+impl Sweeper {
+    pub fn new(config: &SweeperConfig) -> Self {
+        Self {
+            interval: (config.interval_secs > 0).then(|| Duration::from_secs(config.interval_secs)),
+            last_sweep: Instant::now(),
+        }
+    }
+
+    /// Whether a sweep is due at `now`
+    pub fn is_due(&self, now: Instant) -> bool {
+        self.interval
+            .is_some_and(|interval| now.duration_since(self.last_sweep) >= interval)
+    }
+
+    /// Purge the events in `db` that expired or fell outside `retention` by now
+    pub fn run(&mut self, db: &mut DataHolder, retention: &[Retention]) {
+        self.last_sweep = Instant::now();
+        let now = chrono::Utc::now().timestamp() as usize;
+
+        let expired = db.purge_expired(now);
+        if expired > 0 {
+            println!("Purged {} expired events", expired);
+        }
+        let retired = db.apply_retention(retention, now);
+        if retired > 0 {
+            println!("Purged {} events outside the retention rules", retired);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_due_after_interval() {
+        let sweeper = Sweeper::new(&SweeperConfig { interval_secs: 60 });
+        let start = sweeper.last_sweep;
+
+        assert!(!sweeper.is_due(start + Duration::from_secs(59)));
+        assert!(sweeper.is_due(start + Duration::from_secs(60)));
+
+        let off = Sweeper::new(&SweeperConfig { interval_secs: 0 });
+        assert!(!off.is_due(start + Duration::from_secs(3600)));
+    }
+}